@@ -0,0 +1,41 @@
+//! Throughput of large `D` responses, e.g. EXPORT or PKDECRYPT results
+//!
+//!     cargo bench --bench data
+
+#[macro_use]
+extern crate criterion;
+extern crate assuan;
+
+use std::io;
+
+use criterion::{Criterion, Throughput};
+use assuan::AssuanClient;
+
+/// A server reply of `size` bytes split over full length `D` lines
+fn response(size: usize) -> Vec<u8> {
+    let chunk: Vec<u8> = (0..size).map(|i| b'a' + (i % 26) as u8).collect();
+    let mut resp = b"OK\n".to_vec();
+    for line in chunk.chunks(assuan::proto::LINE_LENGTH - 3) {
+        resp.extend_from_slice(&assuan::escape_data_line(line));
+    }
+    resp.extend_from_slice(b"OK\n");
+    resp
+}
+
+fn bulk_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_data");
+    for &size in &[64 * 1024, 4 * 1024 * 1024] {
+        let resp = response(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("{}KiB", size / 1024), |b| {
+            b.iter(|| {
+                let mut client = AssuanClient::new(&resp[..], io::sink()).unwrap();
+                client.exec("EXPORT", &[]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bulk_data);
+criterion_main!(benches);
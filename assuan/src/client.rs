@@ -0,0 +1,1259 @@
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::io::{Error as IoError, ErrorKind};
+use std::io::{Write, Read};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::str;
+
+use std::time::{Duration, Instant};
+
+use constants::status;
+use proto;
+use error::AssuanError;
+use reply::{ReplyEvent, ReplyParser, Response};
+#[cfg(unix)]
+use transport::UnixTransport;
+use transport::{AssuanTransport, ChildTransport, StreamTransport};
+
+/// How a command ended, as passed to `Metrics::record()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// The server replied with ERR and this gpg-error code
+    Server(u32),
+    /// The command failed without a reply from the server, e.g. on an IO
+    /// error
+    Failed,
+}
+
+/// Receives the name, duration and outcome of every command, e.g. to
+/// count commands and errors or to keep latency histograms
+///
+///     # use assuan::{AssuanClient, Outcome};
+///     # use std::sync::{Arc, Mutex};
+///     let errors = Arc::new(Mutex::new(0));
+///     let mut client = AssuanClient::new("OK\nERR 99 Cancelled\n".as_bytes(), Vec::new()).unwrap();
+///     let counter = errors.clone();
+///     client.set_metrics(move |_: &str, _, outcome| if outcome != Outcome::Ok { *counter.lock().unwrap() += 1 });
+///     assert!(client.exec("GETPIN", &[]).is_err());
+///     assert_eq!(*errors.lock().unwrap(), 1);
+///
+/// Arguments are not passed, they may contain secrets.
+pub trait Metrics: Send {
+    fn record(&mut self, command: &str, duration: Duration, outcome: Outcome);
+}
+
+impl<F> Metrics for F where F: FnMut(&str, Duration, Outcome) + Send {
+    fn record(&mut self, command: &str, duration: Duration, outcome: Outcome) {
+        self(command, duration, outcome)
+    }
+}
+
+/// What the client does when the server sends an INQUIRE
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum InquirePolicy {
+    /// Fail the command. The server still waits for an answer, so the
+    /// connection can not be used afterwards.
+    #[default]
+    Error,
+    /// Answer with CAN, the server then usually fails the command
+    Cancel,
+    /// Answer with END, i.e. with empty data
+    End,
+}
+
+/// Answers the INQUIREs sent by the server during a command, see
+/// `AssuanClient::exec_with_inquiries()`
+pub trait InquiryHandler {
+    /// The data to send for `INQUIRE keyword args`, or `None` to cancel
+    /// the inquiry with CAN. `args` is the raw text of the line, still
+    /// percent-escaped so that a `%20` inside one argument doesn't split
+    /// it; use `proto::split_args()` or `unescape_data()` to decode it.
+    fn inquire(&mut self, keyword: &str, args: &str) -> Option<Vec<u8>>;
+}
+
+impl<F> InquiryHandler for F where F: FnMut(&str, &str) -> Option<Vec<u8>> {
+    fn inquire(&mut self, keyword: &str, args: &str) -> Option<Vec<u8>> {
+        self(keyword, args)
+    }
+}
+
+/// Readers whose read timeout can be changed, see
+/// `AssuanClient::exec_timeout()`
+pub trait ReadTimeout {
+    fn read_timeout(&self) -> Result<Option<Duration>, IoError>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError>;
+}
+
+#[cfg(unix)]
+impl ReadTimeout for UnixStream {
+    fn read_timeout(&self) -> Result<Option<Duration>, IoError> {
+        UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl ReadTimeout for TcpStream {
+    fn read_timeout(&self) -> Result<Option<Duration>, IoError> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Where the parts of a reply go besides the `Response`
+#[derive(Default)]
+struct Exchange<'a> {
+    /// Answers INQUIREs instead of the inquire policy
+    handler: Option<&'a mut dyn InquiryHandler>,
+    /// Receives the data as it arrives instead of the `Response`
+    sink: Option<&'a mut dyn Write>,
+}
+
+/// Assuan client, check the Assuan protocol for details
+///
+/// https://www.gnupg.org/documentation/manuals/assuan/index.html
+///
+/// The lines go through an `AssuanTransport`, usually a `StreamTransport`
+/// made by `new()` or a `UnixTransport`.
+pub struct AssuanClient<T> where T: AssuanTransport {
+    transport: T,
+    status: Vec<(String, String)>,
+    inquire_policy: InquirePolicy,
+    max_response: Option<usize>,
+    metrics: Option<Box<dyn Metrics>>,
+    on_status: Option<Box<StatusHook>>,
+    // Set once BYE was sent
+    closed: bool,
+    bye_on_drop: bool,
+}
+
+type StatusHook = dyn FnMut(&str, &str) + Send;
+
+impl AssuanClient<StreamTransport<ChildStdout, ChildStdin>> {
+    /// Take hold of a child's stdin and stdout and use them as communication channel for the
+    /// Assuan protocol
+    ///  
+    /// The child stdin/out must be piped for this to work e.g.
+    ///
+    ///     # use assuan::*;
+    ///     # use std::process::{Command, Child, Stdio, ChildStdin, ChildStdout};
+    ///     let mut cmd = Command::new("pinentry")
+    ///                 .stdin(Stdio::piped())
+    ///                 .stdout(Stdio::piped())
+    ///                 .stderr(Stdio::null())
+    ///                 .spawn()
+    ///                 .unwrap();
+    ///     assert!(AssuanClient::from_child(&mut cmd).is_ok())
+    ///
+    /// It is up to the caller to make sure the child is not killed, and to
+    /// wait for it. See `spawn()` for a client that owns the process.
+    pub fn from_child(c: &mut Child) -> Result<Self, AssuanError> {
+        match (c.stdin.take(), c.stdout.take()) {
+            (Some(w), Some(r)) => Ok(AssuanClient::unconnected(StreamTransport::new(r, w))),
+            _ => Err(AssuanError::Other("Failed to setup stdin/out".to_owned())),
+        }
+    }
+}
+
+impl AssuanClient<ChildTransport> {
+    /// Start `command` with piped stdin and stdout and talk to it, e.g.
+    /// a pinentry. Unlike `from_child()` the client owns the process: on
+    /// drop it sends BYE and waits for the process to exit. Its stderr is
+    /// inherited unless set on `command`.
+    ///
+    /// If the process exits during a command, the error says how it
+    /// exited.
+    pub fn spawn(mut command: Command) -> Result<Self, AssuanError> {
+        let child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        AssuanClient::from_child_owned(child)
+    }
+
+    /// Same as `spawn()` for a child already started with piped stdin and
+    /// stdout.
+    pub fn from_child_owned(child: Child) -> Result<Self, AssuanError> {
+        AssuanClient::with_transport(ChildTransport::new(child)?)
+    }
+}
+
+#[cfg(unix)]
+impl AssuanClient<UnixTransport> {
+    /// Use a connected Unix socket, reading and writing through the same
+    /// socket.
+    pub fn from_unix_stream(stream: UnixStream) -> Result<Self, AssuanError> {
+        AssuanClient::with_transport(UnixTransport::new(stream)?)
+    }
+
+    /// The socket, e.g. to set a read timeout.
+    pub fn get_ref(&self) -> &UnixStream {
+        self.transport.get_ref()
+    }
+}
+
+#[cfg(unix)]
+impl<T> AssuanClient<T> where T: AssuanTransport {
+    /// Pass an open file descriptor to the server, e.g. for gpgsm's
+    /// `INPUT FD` and `OUTPUT FD`, which then take the descriptor sent
+    /// last:
+    ///
+    /// ```no_run
+    /// # use assuan::AssuanClient;
+    /// # use std::fs::File;
+    /// # use std::os::unix::net::UnixStream;
+    /// # let mut client = AssuanClient::from_unix_stream(UnixStream::connect("S.gpgsm").unwrap()).unwrap();
+    /// let input = File::open("message.sig").unwrap();
+    /// client.send_fd(&input).unwrap();
+    /// client.exec("INPUT", &[b"FD"]).unwrap();
+    /// ```
+    ///
+    /// The descriptor stays open here, the server gets its own copy. Only
+    /// some transports can pass descriptors, e.g. `UnixTransport`, and not
+    /// every server accepts them, gpg-agent replies to `INPUT FD` with
+    /// `GPG_ERR_NOT_IMPLEMENTED`.
+    pub fn send_fd<F: AsRawFd>(&mut self, fd: &F) -> Result<(), AssuanError> {
+        debug!("> # descriptor {}", fd.as_raw_fd());
+        self.transport.send_fd(fd.as_raw_fd())?;
+        Ok(())
+    }
+}
+
+impl AssuanClient<StreamTransport<TcpStream, TcpStream>> {
+    /// Use a connected TCP socket, reading and writing through the same
+    /// socket.
+    pub fn from_tcp_stream(stream: TcpStream) -> Result<Self, AssuanError> {
+        AssuanClient::new(stream.try_clone()?, stream)
+    }
+
+    /// Connect to a server listening on TCP, e.g. an agent forwarded from
+    /// another host or a test server.
+    ///
+    /// Unlike gnupg's emulated sockets on Windows, no nonce is sent.
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> Result<Self, AssuanError> {
+        let stream = TcpStream::connect(addr)?;
+        // Commands are short and wait for the reply, do not delay them
+        stream.set_nodelay(true)?;
+        AssuanClient::from_tcp_stream(stream)
+    }
+
+    /// Connect to a socket emulated by libassuan, as gnupg uses on
+    /// Windows. The file at `path` holds the TCP port on localhost and a
+    /// nonce, sent first to prove the client can read the file.
+    ///
+    /// The gpgagent crate does not use this yet, `GpgAgent` only connects
+    /// to Unix sockets.
+    pub fn connect_emulated<P: AsRef<Path>>(path: P) -> Result<Self, AssuanError> {
+        let (port, nonce) = read_port_and_nonce(path.as_ref())?;
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&nonce)?;
+        AssuanClient::from_tcp_stream(stream)
+    }
+}
+
+impl<T> AssuanClient<T> where T: AssuanTransport + ReadTimeout {
+    /// Same as `exec()`, but fail with `AssuanError::Timeout` if the server
+    /// sends nothing for `timeout`, e.g. when a pinentry is never answered.
+    /// The read timeout of the connection is restored afterwards.
+    pub fn exec_timeout(&mut self, name: &str, args: &[&[u8]], timeout: Duration) -> Result<Response, AssuanError> {
+        let previous = self.transport.read_timeout()?;
+        self.transport.set_read_timeout(Some(timeout))?;
+        let res = self.exec(name, args);
+        self.transport.set_read_timeout(previous)?;
+        res
+    }
+}
+
+impl<R, W> AssuanClient<StreamTransport<R, W>> where R: Read, W: Write {
+    /// Creates a new client. Before returning make sure to receive the first
+    /// OK message from the server.
+    pub fn new(r: R, w: W) -> Result<Self, AssuanError> {
+        AssuanClient::with_transport(StreamTransport::new(r, w))
+    }
+
+    /// The reader this client receives responses from, e.g. to set a
+    /// read timeout on a socket.
+    pub fn get_ref(&self) -> &R {
+        self.transport.get_ref()
+    }
+}
+
+impl<T> AssuanClient<T> where T: AssuanTransport {
+    /// A client talking through `transport`, once the server sent its
+    /// first OK.
+    pub fn with_transport(transport: T) -> Result<Self, AssuanError> {
+        let mut p = AssuanClient::unconnected(transport);
+
+        // Wait for server response
+        p.wait_response(Exchange::default())?;
+        Ok(p)
+    }
+
+    fn unconnected(transport: T) -> Self {
+        AssuanClient {
+            transport,
+            status: Vec::new(),
+            inquire_policy: InquirePolicy::default(),
+            max_response: None,
+            metrics: None,
+            on_status: None,
+            closed: false,
+            bye_on_drop: true,
+        }
+    }
+
+    /// The transport of this client.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Whether to send BYE when the client is dropped, true by default.
+    /// Disable it where the server is known to be gone, e.g. when the pipes
+    /// were already closed, so drop does not wait on them.
+    pub fn bye_on_drop(mut self, enabled: bool) -> Self {
+        self.bye_on_drop = enabled;
+        self
+    }
+
+    /// End the session with BYE and close the transport, e.g. waiting for
+    /// the process of `spawn()`. Unlike dropping the client, errors are
+    /// returned.
+    pub fn close(mut self) -> Result<(), AssuanError> {
+        let res = self.bye();
+        // Skip BYE on drop, closing the transport twice does no harm
+        self.bye_on_drop = false;
+        let closed = self.transport.close();
+        res?;
+        closed?;
+        Ok(())
+    }
+
+    /// Execute command with given arguments. Fails without sending
+    /// anything if the escaped command is longer than the servers accept.
+    /// The data is returned unescaped and need not be UTF-8, e.g. for
+    /// signatures.
+    pub fn exec(&mut self, name: &str, args: &[&[u8]]) -> Result<Response, AssuanError> {
+        self.exec_raw(name, args, Exchange::default())
+    }
+
+    /// Same as `exec()`, returning only the data.
+    pub fn exec_data(&mut self, name: &str, args: &[&[u8]]) -> Result<Vec<u8>, AssuanError> {
+        self.exec_raw(name, args, Exchange::default()).map(|res| res.data)
+    }
+
+    /// Same as `exec()`, answering the INQUIREs of the command with
+    /// `handler` instead of the inquire policy e.g.
+    ///
+    ///     # use assuan::AssuanClient;
+    ///     let mut sent = Vec::new();
+    ///     {
+    ///         let mut client = AssuanClient::new("OK\nINQUIRE KEYPARAM\nOK\n".as_bytes(), &mut sent).unwrap();
+    ///         client.exec_with_inquiries("GENKEY", &[], |keyword: &str, _: &str| match keyword {
+    ///             "KEYPARAM" => Some(b"(genkey(ecc(curve 7:Ed25519)))".to_vec()),
+    ///             _ => None,
+    ///         }).unwrap();
+    ///     }
+    ///     assert!(sent.starts_with(b"GENKEY\nD (genkey(ecc(curve 7:Ed25519)))\nEND\n"));
+    ///
+    /// The data is split over as many D lines as needed. If the server
+    /// limited its size with an `INQUIRE_MAXLEN` status, longer data is
+    /// not sent, the inquiry is canceled and the command fails.
+    pub fn exec_with_inquiries<H>(&mut self, name: &str, args: &[&[u8]], mut handler: H)
+        -> Result<Response, AssuanError>
+        where H: InquiryHandler
+    {
+        self.exec_raw(name, args, Exchange { handler: Some(&mut handler), sink: None })
+    }
+
+    /// Same as `exec_with_inquiries()`, answering the first INQUIRE with
+    /// `data` e.g. the key for `IMPORT_KEY`. Any later inquiry is canceled.
+    pub fn exec_with_data(&mut self, name: &str, args: &[&[u8]], data: &[u8]) -> Result<Response, AssuanError> {
+        let mut data = Some(data);
+        self.exec_with_inquiries(name, args, |_: &str, _: &str| data.take().map(|data| data.to_vec()))
+    }
+
+    /// Same as `exec()`, writing the data to `sink` one D line at a time
+    /// instead of collecting it in the `Response`, e.g. for `EXPORT_KEY`.
+    /// If writing fails, the rest of the reply is read and the write error
+    /// is returned.
+    pub fn exec_streaming<S: Write>(&mut self, name: &str, args: &[&[u8]], sink: &mut S)
+        -> Result<Response, AssuanError>
+    {
+        self.exec_raw(name, args, Exchange { handler: None, sink: Some(sink) })
+    }
+
+    fn exec_raw(&mut self, name: &str, args: &[&[u8]], exchange: Exchange)
+        -> Result<Response, AssuanError>
+    {
+        check_command_name(name)?;
+        let command = proto::encode_command(name, args);
+        // The limit includes the newline
+        if command.len() >= proto::LINE_LENGTH {
+            return Err(AssuanError::Other(format!("{} command is {} bytes long once escaped, the limit is {}",
+                                                  name, command.len() + 1, proto::LINE_LENGTH)));
+        }
+        self.call(&command, exchange)
+    }
+
+    fn call(&mut self, command: &str, exchange: Exchange) -> Result<Response, AssuanError> {
+        if self.metrics.is_none() {
+            return self.traced_call(command, exchange);
+        }
+        let start = Instant::now();
+        let res = self.traced_call(command, exchange);
+        let outcome = match res {
+            Ok(_) => Outcome::Ok,
+            Err(AssuanError::Server { code, .. }) => Outcome::Server(code),
+            Err(_) => Outcome::Failed,
+        };
+        if let Some(ref mut metrics) = self.metrics {
+            metrics.record(command.split(' ').next().unwrap_or(""), start.elapsed(), outcome);
+        }
+        res
+    }
+
+    /// Run the command inside a tracing span. Only the command name is
+    /// recorded, arguments may contain secrets.
+    #[cfg(feature = "tracing")]
+    fn traced_call(&mut self, command: &str, exchange: Exchange)
+        -> Result<Response, AssuanError>
+    {
+        let name = command.split(' ').next().unwrap_or("");
+        let span = tracing::debug_span!("assuan_command",
+                                        command = name,
+                                        duration_ms = tracing::field::Empty,
+                                        outcome = tracing::field::Empty);
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let res = self.send_command(command, exchange);
+        span.record("duration_ms", start.elapsed().as_millis() as u64);
+        let outcome = match res {
+            Ok(_) => "OK".to_owned(),
+            // The ERR code, the description may echo arguments back
+            Err(AssuanError::Server { code, .. }) => code.to_string(),
+            Err(AssuanError::IoError(_)) => "IO".to_owned(),
+            Err(AssuanError::Timeout) => "TIMEOUT".to_owned(),
+            Err(_) => "ERR".to_owned(),
+        };
+        span.record("outcome", outcome.as_str());
+        res
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn traced_call(&mut self, command: &str, exchange: Exchange)
+        -> Result<Response, AssuanError>
+    {
+        self.send_command(command, exchange)
+    }
+
+    fn send_command(&mut self, command: &str, exchange: Exchange)
+        -> Result<Response, AssuanError>
+    {
+        debug!("> {}", command);
+        self.transport.write_lines(format!("{}\n", command).as_bytes())?;
+        self.transport.flush()?;
+
+        self.wait_response(exchange)
+    }
+
+    /// The commands the server lists in its reply to `HELP`, in upper
+    /// case, e.g. to check a restricted socket supports a command before
+    /// using it.
+    pub fn commands(&mut self) -> Result<HashSet<String>, AssuanError> {
+        let res = self.exec("HELP", &[])?;
+        Ok(res.comments.iter()
+           .filter_map(|line| line.split(' ').next())
+           .filter(|name| !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_'))
+           .map(|name| name.to_ascii_uppercase())
+           .collect())
+    }
+
+    /// Send `RESET`, the server forgets the state of the session e.g. the
+    /// options.
+    pub fn reset(&mut self) -> Result<(), AssuanError> {
+        self.exec("RESET", &[]).map(|_| ())
+    }
+
+    /// Send `NOP`, e.g. to check the server is still there.
+    pub fn nop(&mut self) -> Result<(), AssuanError> {
+        self.exec("NOP", &[]).map(|_| ())
+    }
+
+    /// Answer an INQUIRE with `CAN`, when driving a command with
+    /// `send_line()`.
+    pub fn cancel(&mut self) -> Result<(), AssuanError> {
+        self.send_line("CAN")
+    }
+
+    /// End the data answering an INQUIRE with `END`, when driving a
+    /// command with `send_line()`.
+    pub fn end(&mut self) -> Result<(), AssuanError> {
+        self.send_line("END")
+    }
+
+    /// Send `BYE` and wait for the server to close the session. Nothing
+    /// is sent on drop afterwards.
+    pub fn bye(&mut self) -> Result<(), AssuanError> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.call("BYE", Exchange::default()).map(|_| ())
+    }
+
+    /// Send `OPTION name value`. The name is not escaped, so it must not
+    /// be empty or contain spaces, `=` or control characters.
+    pub fn option<N, V>(&mut self, name: N, val: V) -> Result<(), AssuanError>
+        where N: AsRef<[u8]>, V: AsRef<[u8]>
+    {
+        check_option_name(name.as_ref())?;
+        self.exec("OPTION", &[name.as_ref(), val.as_ref()]).map(|_| ())
+    }
+
+    /// Send `OPTION name` without a value, the form used for flags.
+    pub fn option_flag<N: AsRef<[u8]>>(&mut self, name: N) -> Result<(), AssuanError> {
+        check_option_name(name.as_ref())?;
+        self.exec("OPTION", &[name.as_ref()]).map(|_| ())
+    }
+
+    /// Set a flag option. Flags are off unless given, so `OPTION name` is
+    /// sent if `val` is true and nothing is sent otherwise.
+    pub fn option_bool<N: AsRef<[u8]>>(&mut self, name: N, val: bool) -> Result<(), AssuanError> {
+        if val {
+            self.option_flag(name)
+        } else {
+            check_option_name(name.as_ref())
+        }
+    }
+
+    /// Report every command to `metrics`, replacing any previous one.
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        self.metrics = Some(Box::new(metrics));
+    }
+
+    /// Call `hook` with the keyword and arguments of every status line as
+    /// it arrives, e.g. to show `PROGRESS` while a command runs. The
+    /// arguments are still escaped. This replaces any previous hook. The
+    /// lines are still kept for `status_lines()`.
+    pub fn on_status<F>(&mut self, hook: F) where F: FnMut(&str, &str) + Send + 'static {
+        self.on_status = Some(Box::new(hook));
+    }
+
+    /// Set how INQUIREs are answered, by default they fail the command.
+    pub fn set_inquire_policy(&mut self, policy: InquirePolicy) {
+        self.inquire_policy = policy;
+    }
+
+    /// Fail with `AssuanError::ResponseTooLarge` once the data of a reply
+    /// exceeds `limit` bytes, unescaped. There is no limit by default. Data
+    /// written to a sink by `exec_streaming()` does not count.
+    pub fn set_max_response_size(&mut self, limit: Option<usize>) {
+        self.max_response = limit;
+    }
+
+    /// The status lines (keyword, arguments) sent by the server during
+    /// the last command. The arguments are the raw text of each line, still
+    /// percent-escaped; decode them with `proto::split_args()`.
+    pub fn status_lines(&self) -> &[(String, String)] {
+        &self.status
+    }
+
+    /// Read the reply to a line sent with `send_line()`, up to its OK or
+    /// ERR. INQUIREs are answered following the inquire policy.
+    pub fn read_response(&mut self) -> Result<Response, AssuanError> {
+        self.wait_response(Exchange::default())
+    }
+
+    fn wait_response(&mut self, mut exchange: Exchange) -> Result<Response, AssuanError> {
+        let mut parser = ReplyParser::new();
+        if let Some(limit) = self.max_response {
+            parser = parser.max_data(limit);
+        }
+        // Set when inquired data was not sent for being too long
+        let mut too_long = None;
+        let mut sink_err = None;
+        let mut line = Vec::new();
+        self.status.clear();
+
+        loop {
+            // Read lines until we get an ERR or an OK
+            line.clear();
+            match self.transport.read_line(&mut line) {
+                Ok(0) => return Err(AssuanError::IoError(IoError::new(ErrorKind::UnexpectedEof,
+                                                                      "Connection closed by the server"))),
+                Ok(_) => (),
+                // The error for a read timeout depends on the platform
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
+                    return Err(AssuanError::Timeout);
+                }
+                Err(err) => return Err(err.into()),
+            }
+            debug!("< {}", String::from_utf8_lossy(&line));
+
+            if let Some(ref mut sink) = exchange.sink {
+                if let Some(proto::Line::Data(escaped)) = proto::parse_line(line.strip_suffix(b"\n").unwrap_or(&line)) {
+                    // Keep reading the reply after a write error
+                    if sink_err.is_none() {
+                        sink_err = sink.write_all(&proto::unescape_data(escaped)).err();
+                    }
+                    continue;
+                }
+            }
+
+            match parser.feed(&line) {
+                None => (),
+                Some(ReplyEvent::Done(_)) if sink_err.is_some() => {
+                    return Err(sink_err.take().unwrap().into());
+                }
+                // The server fails the inquiry canceled for being too long
+                Some(ReplyEvent::Done(res)) => return match (res, too_long) {
+                    (Err(AssuanError::Server { .. }), Some(err)) => Err(err),
+                    (res, _) => res,
+                },
+                Some(ReplyEvent::Status { keyword, args }) => {
+                    if let Some(ref mut hook) = self.on_status {
+                        hook(&keyword, &args);
+                    }
+                    self.status.push((keyword, args));
+                }
+                Some(ReplyEvent::Inquire { keyword, args }) => {
+                    if let Some(ref mut handler) = exchange.handler {
+                        let answer = handler.inquire(&keyword, &args);
+                        too_long = self.send_inquired(&keyword, answer)?.or(too_long);
+                        continue;
+                    }
+                    let answer = match self.inquire_policy {
+                        InquirePolicy::Error => return Err(AssuanError::Other("Received unsupported INQUIRE message"
+                                                                               .to_owned())),
+                        InquirePolicy::Cancel => "CAN",
+                        InquirePolicy::End => "END",
+                    };
+                    self.send_line(answer)?;
+                }
+            }
+        }
+    }
+
+    /// Send the answer to an INQUIRE, returns the error to report if the
+    /// data was not sent for being longer than `INQUIRE_MAXLEN`.
+    fn send_inquired(&mut self, keyword: &str, data: Option<Vec<u8>>) -> Result<Option<AssuanError>, AssuanError> {
+        let data = match data {
+            Some(data) => data,
+            None => {
+                self.send_line("CAN")?;
+                return Ok(None);
+            }
+        };
+        let maxlen = self.status.iter().rev()
+            .find(|&(keyword, _)| keyword == status::INQUIRE_MAXLEN)
+            .and_then(|(_, args)| args.trim().parse::<usize>().ok());
+        if let Some(maxlen) = maxlen.filter(|&maxlen| data.len() > maxlen) {
+            self.send_line("CAN")?;
+            return Ok(Some(AssuanError::Other(format!("{} is {} bytes long, the server accepts at most {}",
+                                                      keyword, data.len(), maxlen))));
+        }
+        self.transport.write_lines(&proto::escape_data_lines(&data))?;
+        debug!("> D [{} bytes]", data.len());
+        self.send_line("END")?;
+        Ok(None)
+    }
+
+    /// Send `line` as it is, for exchanges `exec()` does not cover. The
+    /// newline is added, the line must already be escaped. Read the reply
+    /// with `read_response()`.
+    ///
+    ///     # use assuan::AssuanClient;
+    ///     let mut sent = Vec::new();
+    ///     {
+    ///         let mut client = AssuanClient::new("OK\nD 42\nOK\n".as_bytes(), &mut sent).unwrap();
+    ///         client.send_line("GETINFO pid").unwrap();
+    ///         assert_eq!(client.read_response().unwrap().data, b"42");
+    ///     }
+    ///     assert!(sent.starts_with(b"GETINFO pid\n"));
+    pub fn send_line(&mut self, line: &str) -> Result<(), AssuanError> {
+        if line.contains('\n') || line.len() >= proto::LINE_LENGTH {
+            return Err(AssuanError::Other(format!("Not a valid Assuan line: {}", line.escape_debug())));
+        }
+        debug!("> {}", line);
+        self.transport.write_lines(format!("{}\n", line).as_bytes())?;
+        self.transport.flush()?;
+        Ok(())
+    }
+}
+
+/// Read the file of an emulated socket: the port in decimal, a newline and
+/// 16 bytes of nonce
+fn read_port_and_nonce(path: &Path) -> Result<(u16, [u8; 16]), AssuanError> {
+    let invalid = || AssuanError::Other(format!("{} is not an emulated Assuan socket", path.display()));
+    let contents = fs::read(path)?;
+    let newline = contents.iter().position(|&b| b == b'\n').ok_or_else(invalid)?;
+    let port = str::from_utf8(&contents[..newline]).ok()
+        .and_then(|port| port.trim().parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+    let mut nonce = [0; 16];
+    if contents.len() - newline - 1 != nonce.len() {
+        return Err(invalid());
+    }
+    nonce.copy_from_slice(&contents[newline + 1..]);
+    Ok((port, nonce))
+}
+
+fn check_command_name(name: &str) -> Result<(), AssuanError> {
+    if name.is_empty() || name.bytes().any(|b| b == b' ' || !b.is_ascii_graphic()) {
+        return Err(AssuanError::InvalidCommand(name.to_owned()));
+    }
+    Ok(())
+}
+
+fn check_option_name(name: &[u8]) -> Result<(), AssuanError> {
+    if name.is_empty() || name.iter().any(|&b| b == b' ' || b == b'=' || b.is_ascii_control()) {
+        return Err(AssuanError::Other(format!("Invalid option name: {}",
+                                              String::from_utf8_lossy(name).escape_debug())));
+    }
+    Ok(())
+}
+
+impl<T> Drop for AssuanClient<T> where T: AssuanTransport {
+    fn drop(&mut self) {
+        if self.bye_on_drop {
+            let _ = self.bye();
+        }
+        let _ = self.transport.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+    use constants::error;
+
+    #[test]
+    fn into_io_error() {
+        let err = IoError::from(AssuanError::server(67109115, "Forbidden <GPG Agent>"));
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(err.to_string(), "67109115 Forbidden <GPG Agent>");
+
+        let err = IoError::from(AssuanError::server(67108926, "Timeout"));
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let refused = IoError::new(ErrorKind::ConnectionRefused, "refused");
+        let err = IoError::from(AssuanError::IoError(refused));
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn error_source() {
+        let err = AssuanError::from(IoError::new(ErrorKind::ConnectionRefused, "refused"));
+        let source = err.source().unwrap().downcast_ref::<IoError>().unwrap();
+        assert_eq!(source.kind(), ErrorKind::ConnectionRefused);
+        assert!(AssuanError::server(99, "Canceled").source().is_none());
+
+        let boxed: Box<dyn StdError> = Box::new(AssuanError::server(99, "Canceled"));
+        assert_eq!(boxed.to_string(), "99 Canceled");
+    }
+
+    #[test]
+    fn exec_escapes_arguments() {
+        let mut sent = Vec::new();
+        {
+            let mut client = AssuanClient::new("OK\nOK\n".as_bytes(), &mut sent).unwrap();
+            client.exec("SETDESC", &["café 1+1%\n".as_bytes(), b"\xff"]).unwrap();
+        }
+        assert_eq!(String::from_utf8(sent).unwrap(),
+                   "SETDESC caf%C3%A9%201%2B1%25%0A %FF\nBYE\n");
+    }
+
+    #[test]
+    fn binary_data() {
+        let mut client = AssuanClient::new(&b"OK\nD \x00\xff%25\nD %0A\nOK\nD 100%25\nOK done\n"[..],
+                                           Vec::new()).unwrap();
+        assert_eq!(client.exec_data("PKSIGN", &[]).unwrap(), b"\x00\xff%\n");
+        let res = client.exec("GETINFO", &[]).unwrap();
+        assert_eq!((res.message.as_str(), res.data.as_slice()), ("done", &b"100%"[..]));
+    }
+
+    #[test]
+    fn line_too_long() {
+        let mut sent = Vec::new();
+        {
+            let mut client = AssuanClient::new("OK\nOK\n".as_bytes(), &mut sent).unwrap();
+            // 1 + 1 + 998 bytes and the newline
+            assert!(client.exec("D", &[&[b'a'; 998]]).is_err());
+            client.exec("D", &[&[b'a'; 997]]).unwrap();
+            for name in ["", "GET PIN", "GETPIN\n", "GET\tPIN"] {
+                match client.exec(name, &[]) {
+                    Err(AssuanError::InvalidCommand(ref invalid)) if invalid == name => (),
+                    res => panic!("{:?}", res.map(|_| ())),
+                }
+            }
+        }
+        assert_eq!(sent.len(), 1000 + "BYE\n".len());
+    }
+
+    #[test]
+    fn option_forms() {
+        let mut sent = Vec::new();
+        {
+            let mut client = AssuanClient::new("OK\nOK\nOK\nOK\n".as_bytes(), &mut sent).unwrap();
+            client.option("display", ":0").unwrap();
+            client.option_flag("allow-pinentry-notify").unwrap();
+            client.option_bool("no-grab", true).unwrap();
+            client.option_bool("grab", false).unwrap();
+            assert!(client.option("lc ctype", "C").is_err());
+            assert!(client.option_bool("grab=1", false).is_err());
+        }
+        assert_eq!(String::from_utf8(sent).unwrap(),
+                   "OPTION display :0\nOPTION allow-pinentry-notify\nOPTION no-grab\nBYE\n");
+    }
+
+    #[test]
+    fn response() {
+        let replies = "OK\nS PROGRESS a 1\nD x\nS KEYINFO k\nS PROGRESS a 2\nOK Fine\n";
+        let mut client = AssuanClient::new(replies.as_bytes(), Vec::new()).unwrap();
+        let res = client.exec("KEYINFO", &[]).unwrap();
+        assert_eq!(res, Response {
+            message: "Fine".to_owned(),
+            data: b"x".to_vec(),
+            status: vec![("PROGRESS".to_owned(), "a 1".to_owned()),
+                         ("KEYINFO".to_owned(), "k".to_owned()),
+                         ("PROGRESS".to_owned(), "a 2".to_owned())],
+            comments: Vec::new(),
+        });
+        assert_eq!(res.status("PROGRESS").collect::<Vec<_>>(), ["a 1", "a 2"]);
+    }
+
+    #[test]
+    fn status_hook() {
+        use std::sync::{Arc, Mutex};
+
+        let replies = "OK\nS PROGRESS x%20y 1 2\nINQUIRE PASSPHRASE\nS PINENTRY_LAUNCHED 42\nOK\n";
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut client = AssuanClient::new(replies.as_bytes(), Vec::new()).unwrap();
+        {
+            let seen = seen.clone();
+            client.on_status(move |keyword: &str, args: &str| seen.lock().unwrap().push(format!("{} {}", keyword, args)));
+        }
+        // The first status is seen before the inquiry is answered
+        let res = client.exec_with_inquiries("GENKEY", &[], |_: &str, _: &str| {
+            assert_eq!(seen.lock().unwrap().len(), 1);
+            None
+        });
+        assert!(res.is_ok());
+        // The arguments stay escaped, so "x y" is still one field
+        assert_eq!(*seen.lock().unwrap(), ["PROGRESS x%20y 1 2", "PINENTRY_LAUNCHED 42"]);
+        assert_eq!(client.status_lines().len(), 2);
+        assert_eq!(::proto::split_args(client.status_lines()[0].1.as_bytes()), [&b"x y"[..], b"1", b"2"]);
+    }
+
+    #[test]
+    fn localized_errors() {
+        let mut client = AssuanClient::new(&b"OK\nERR 83886179 Op\xe9ration annul%C3%A9e %3CPinentry%3E\n"[..],
+                                           Vec::new()).unwrap();
+        match client.exec("GETPIN", &[]).unwrap_err() {
+            AssuanError::Server { description, .. } => assert_eq!(description, "Op\u{fffd}ration annulée <Pinentry>"),
+            err => panic!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn errors_keep_raw_line() {
+        let mut client = AssuanClient::new(&b"OK\nERR 83886179 Operation cancelled <Pinentry>\n\xffbad\n"[..],
+                                           Vec::new()).unwrap();
+
+        let err = client.exec("GETPIN", &[]).unwrap_err();
+        assert_eq!(err.code(), Some(83886179));
+        assert_eq!(err.error_code(), Some(error::CANCELED));
+        assert_eq!(err.raw_line(), Some(&b"ERR 83886179 Operation cancelled <Pinentry>"[..]));
+
+        let err = client.exec("NOP", &[]).unwrap_err();
+        assert!(err.code().is_none() && err.error_source().is_none());
+        assert_eq!(err.raw_line(), Some(&b"\xffbad"[..]));
+    }
+
+    #[test]
+    fn inquire_policy() {
+        let replies = "OK\nINQUIRE KEYPARAM\nINQUIRE KEYPARAM\nERR 99 Canceled\nINQUIRE KEYPARAM\nOK\n";
+        let mut sent = Vec::new();
+        {
+            let mut client = AssuanClient::new(replies.as_bytes(), &mut sent).unwrap();
+            assert!(client.exec("GENKEY", &[]).is_err());
+            client.set_inquire_policy(InquirePolicy::Cancel);
+            assert_eq!(client.exec("GENKEY", &[]).unwrap_err().code(), Some(99));
+            client.set_inquire_policy(InquirePolicy::End);
+            client.exec("GENKEY", &[]).unwrap();
+        }
+        assert_eq!(String::from_utf8(sent).unwrap(),
+                   "GENKEY\nGENKEY\nCAN\nGENKEY\nEND\nBYE\n");
+    }
+
+    #[test]
+    fn inquiries() {
+        let replies = "OK\nINQUIRE KEYPARAM x\nINQUIRE OTHER\nD done\nOK\n\
+                       S INQUIRE_MAXLEN 4\nINQUIRE PASSPHRASE\nERR 99 Canceled\n";
+        let mut sent = Vec::new();
+        // The most escaped bytes that fit a line
+        let chunk = (proto::LINE_LENGTH - 3) / 3;
+        let big = vec![b'%'; chunk + 1];
+        {
+            let mut client = AssuanClient::new(replies.as_bytes(), &mut sent).unwrap();
+            let mut asked = Vec::new();
+            let res = client.exec_with_inquiries("GENKEY", &[], |keyword: &str, args: &str| {
+                asked.push(format!("{} {}", keyword, args));
+                match keyword {
+                    "KEYPARAM" => Some(big.clone()),
+                    _ => None,
+                }
+            }).unwrap();
+            assert_eq!(res.data, b"done");
+            assert_eq!(asked, ["KEYPARAM x", "OTHER "]);
+
+            let err = client.exec_with_inquiries("PRESET_PASSPHRASE", &[], |_: &str, _: &str| Some(b"hunter2".to_vec()))
+                .unwrap_err();
+            assert!(err.to_string().contains("at most 4"));
+        }
+        let sent = String::from_utf8(sent).unwrap();
+        let lines: Vec<&str> = sent.lines().collect();
+        assert_eq!(lines[0], "GENKEY");
+        assert_eq!(lines[1].len(), 2 + 3 * chunk);
+        assert!(lines[1].len() < proto::LINE_LENGTH);
+        assert_eq!(&lines[2..], ["D %25", "END", "CAN", "PRESET_PASSPHRASE", "CAN", "BYE"]);
+    }
+
+    #[test]
+    fn with_data() {
+        let mut sent = Vec::new();
+        {
+            let mut client = AssuanClient::new("OK\nINQUIRE KEYDATA\nS IMPORTED 1\nINQUIRE MORE\nD ok\nOK\n".as_bytes(),
+                                               &mut sent).unwrap();
+            let res = client.exec_with_data("IMPORT_KEY", &[], b"100%\n").unwrap();
+            assert_eq!(res.data, b"ok");
+            assert_eq!(res.status("IMPORTED").collect::<Vec<_>>(), ["1"]);
+        }
+        assert_eq!(sent, b"IMPORT_KEY\nD 100%25%0A\nEND\nCAN\nBYE\n");
+    }
+
+    #[test]
+    fn raw_lines() {
+        let mut sent = Vec::new();
+        {
+            let mut client = AssuanClient::new("OK\nS PROGRESS 1\nOK\nERR 99 No\n".as_bytes(), &mut sent).unwrap();
+            assert!(client.send_line("A\nB").is_err());
+            assert!(client.send_line(&"A".repeat(proto::LINE_LENGTH)).is_err());
+            client.send_line("SCD SERIALNO openpgp").unwrap();
+            assert_eq!(client.read_response().unwrap().status, [("PROGRESS".to_owned(), "1".to_owned())]);
+            assert_eq!(client.read_response().unwrap_err().code(), Some(99));
+        }
+        assert_eq!(sent, b"SCD SERIALNO openpgp\nBYE\n");
+    }
+
+    #[test]
+    fn core_commands() {
+        let mut sent = Vec::new();
+        {
+            let replies = "OK\nOK\nOK\nOK\nERR 99 Canceled\nOK closing\n";
+            let mut client = AssuanClient::new(replies.as_bytes(), &mut sent).unwrap();
+            client.reset().unwrap();
+            client.nop().unwrap();
+            client.send_line("SETPIN").unwrap();
+            client.end().unwrap();
+            client.read_response().unwrap();
+            client.send_line("SETPIN").unwrap();
+            client.cancel().unwrap();
+            assert!(client.read_response().is_err());
+            client.bye().unwrap();
+            // Only once
+            client.bye().unwrap();
+        }
+        assert_eq!(sent, b"RESET\nNOP\nSETPIN\nEND\nSETPIN\nCAN\nBYE\n");
+    }
+
+    #[test]
+    fn commands() {
+        let replies = "OK\n# NOP\n# GETINFO <what>\n# \n# SCD\nOK\n";
+        let mut client = AssuanClient::new(replies.as_bytes(), Vec::new()).unwrap();
+        let commands = client.commands().unwrap();
+        let expected = ["NOP", "GETINFO", "SCD"].iter().map(|&name| name.to_owned()).collect();
+        assert_eq!(commands, expected);
+    }
+
+    #[test]
+    fn streaming() {
+        let replies = "OK\nD 100%25\nS PROGRESS 1\nD %0Adone\nOK\nD lost\nOK\nD x\nOK\n";
+        let mut client = AssuanClient::new(replies.as_bytes(), Vec::new()).unwrap();
+        let mut sink = Vec::new();
+        let res = client.exec_streaming("EXPORT_KEY", &[], &mut sink).unwrap();
+        assert_eq!(sink, b"100%\ndone");
+        assert!(res.data.is_empty());
+        assert_eq!(res.status, [("PROGRESS".to_owned(), "1".to_owned())]);
+
+        let mut full = [0u8; 2];
+        let err = client.exec_streaming("EXPORT_KEY", &[], &mut &mut full[..]).unwrap_err();
+        assert_eq!(IoError::from(err).kind(), ErrorKind::WriteZero);
+        // The session is still usable
+        assert_eq!(client.exec_data("NOP", &[]).unwrap(), b"x");
+    }
+
+    #[test]
+    fn max_response_size() {
+        let replies = "OK\nD 1234\nD %25\nOK\nD 12345\nD 6\nOK\n";
+        let mut client = AssuanClient::new(replies.as_bytes(), Vec::new()).unwrap();
+        client.set_max_response_size(Some(5));
+        assert_eq!(client.exec_data("GETINFO", &[b"a"]).unwrap(), b"1234%");
+        match client.exec("GETINFO", &[b"b"]) {
+            Err(AssuanError::ResponseTooLarge(5)) => (),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn timeout() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut server_w = server.try_clone().unwrap();
+        server_w.write_all(b"OK\nOK\n").unwrap();
+        let mut client = AssuanClient::from_unix_stream(client).unwrap();
+
+        client.exec_timeout("NOP", &[], Duration::from_millis(100)).unwrap();
+        match client.exec_timeout("GETPIN", &[], Duration::from_millis(50)) {
+            Err(AssuanError::Timeout) => (),
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(client.get_ref().read_timeout().unwrap(), None);
+        assert_eq!(IoError::from(AssuanError::Timeout).kind(), ErrorKind::TimedOut);
+        // Do not wait for the reply to BYE
+        client.get_ref().set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+    }
+
+    #[test]
+    fn owned_streams() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        // A server that greets, answers one command and the BYE
+        fn serve<S: Read + Write>(mut stream: S) -> String {
+            stream.write_all(b"OK\nOK\nOK\n").unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).unwrap();
+            received
+        }
+
+        #[cfg(unix)]
+        {
+            let (client, server) = UnixStream::pair().unwrap();
+            let server = thread::spawn(move || serve(server));
+            AssuanClient::from_unix_stream(client).unwrap().exec("NOP", &[]).unwrap();
+            assert_eq!(server.join().unwrap(), "NOP\nBYE\n");
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = listener.try_clone().unwrap();
+        let server = thread::spawn(move || serve(accept.accept().unwrap().0));
+        AssuanClient::from_tcp_stream(TcpStream::connect(addr).unwrap()).unwrap().exec("NOP", &[]).unwrap();
+        assert_eq!(server.join().unwrap(), "NOP\nBYE\n");
+
+        let accept = listener.try_clone().unwrap();
+        let server = thread::spawn(move || serve(accept.accept().unwrap().0));
+        AssuanClient::connect_tcp(addr).unwrap().exec("NOP", &[]).unwrap();
+        assert_eq!(server.join().unwrap(), "NOP\nBYE\n");
+        drop(listener);
+        assert!(AssuanClient::connect_tcp(addr).is_err());
+    }
+
+    #[test]
+    fn emulated_socket() {
+        use std::env;
+        use std::net::TcpListener;
+        use std::process;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let nonce = *b"0123456789abcdef";
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut received = [0; 16];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(received, nonce);
+            stream.write_all(b"OK\nOK\nOK\n").unwrap();
+            let mut commands = String::new();
+            stream.read_to_string(&mut commands).unwrap();
+            commands
+        });
+
+        let path = env::temp_dir().join(format!("assuan-emulated-{}", process::id()));
+        fs::write(&path, [format!("{}\n", port).as_bytes(), &nonce].concat()).unwrap();
+        let mut client = AssuanClient::connect_emulated(&path).unwrap();
+        client.nop().unwrap();
+        drop(client);
+        assert_eq!(server.join().unwrap(), "NOP\nBYE\n");
+
+        fs::write(&path, format!("{}\nshort", port)).unwrap();
+        assert!(read_port_and_nonce(&path).unwrap_err().to_string().contains("not an emulated"));
+        fs::remove_file(&path).unwrap();
+        assert!(AssuanClient::connect_emulated(&path).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pass_fd() {
+        use std::fs::File;
+        use std::mem;
+        use std::os::unix::io::FromRawFd;
+        use std::thread;
+
+        // Receive one line with the descriptor passed along
+        fn recv_fd(sock: &UnixStream) -> (Vec<u8>, File) {
+            let mut buf = [0u8; 128];
+            let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+            let mut control = [0u64; 8];
+            unsafe {
+                let mut msg: libc::msghdr = mem::zeroed();
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = mem::size_of_val(&control) as _;
+                let len = libc::recvmsg(sock.as_raw_fd(), &mut msg, 0);
+                assert!(len > 0);
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                assert_eq!((*cmsg).cmsg_type, libc::SCM_RIGHTS);
+                let fd = (libc::CMSG_DATA(cmsg) as *const i32).read_unaligned();
+                (buf[..len as usize].to_vec(), File::from_raw_fd(fd))
+            }
+        }
+
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            server.write_all(b"OK\n").unwrap();
+            let (line, mut file) = recv_fd(&server);
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).unwrap();
+            server.write_all(b"OK\nOK\n").unwrap();
+            let mut commands = String::new();
+            server.read_to_string(&mut commands).unwrap();
+            (line, contents, commands)
+        });
+
+        let mut client = AssuanClient::from_unix_stream(client).unwrap();
+        let input = File::open("Cargo.toml").unwrap();
+        client.send_fd(&input).unwrap();
+        client.exec("INPUT", &[b"FD"]).unwrap();
+        drop(client);
+
+        let (line, contents, commands) = server.join().unwrap();
+        assert_eq!(line, format!("# descriptor {} is in flight\n", input.as_raw_fd()).into_bytes());
+        assert!(contents.starts_with("[package]"));
+        assert_eq!(commands, "INPUT FD\nBYE\n");
+    }
+
+    #[test]
+    fn custom_transport() {
+        use std::collections::VecDeque;
+
+        /// Replies with the scripted lines, keeping what was sent
+        struct Scripted {
+            replies: VecDeque<&'static [u8]>,
+            sent: Vec<u8>,
+        }
+
+        impl AssuanTransport for Scripted {
+            fn read_line(&mut self, line: &mut Vec<u8>) -> Result<usize, IoError> {
+                let reply = self.replies.pop_front().unwrap_or(b"");
+                line.extend_from_slice(reply);
+                Ok(reply.len())
+            }
+
+            fn write_lines(&mut self, lines: &[u8]) -> Result<(), IoError> {
+                self.sent.extend_from_slice(lines);
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), IoError> {
+                Ok(())
+            }
+        }
+
+        let transport = Scripted {
+            replies: vec![&b"OK\n"[..], b"D 1%0A\n", b"OK\n", b"OK\n"].into(),
+            sent: Vec::new(),
+        };
+        let mut client = AssuanClient::with_transport(transport).unwrap();
+        assert_eq!(client.exec_data("GETINFO", &[b"pid"]).unwrap(), b"1\n");
+        assert_eq!(client.transport().sent, b"GETINFO pid\n");
+        #[cfg(unix)]
+        {
+            let input = std::fs::File::open("Cargo.toml").unwrap();
+            assert_eq!(IoError::from(client.send_fd(&input).unwrap_err()).kind(), ErrorKind::Unsupported);
+        }
+        client.bye().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn owned_child() {
+        use std::process::Command;
+
+        // Answers the greeting, NOP and BYE
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo OK; read nop; echo OK; read bye; echo OK"]);
+        let mut client = AssuanClient::spawn(command).unwrap();
+        client.nop().unwrap();
+        client.bye().unwrap();
+        drop(client);
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo OK; read cmd; exit 3"]);
+        let mut client = AssuanClient::spawn(command).unwrap();
+        let err = client.exec("GETPIN", &[]).unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"), "{}", err);
+
+        let child = Command::new("true").spawn().unwrap();
+        assert!(AssuanClient::from_child_owned(child).is_err());
+    }
+
+    #[test]
+    fn close() {
+        let mut sent = Vec::new();
+        AssuanClient::new("OK\nOK\n".as_bytes(), &mut sent).unwrap().close().unwrap();
+        assert_eq!(sent, b"BYE\n");
+
+        // The server is gone before BYE
+        let err = AssuanClient::new("OK\n".as_bytes(), Vec::new()).unwrap().close().unwrap_err();
+        assert_eq!(IoError::from(err).kind(), ErrorKind::UnexpectedEof);
+
+        let mut sent = Vec::new();
+        drop(AssuanClient::new("OK\n".as_bytes(), &mut sent).unwrap().bye_on_drop(false));
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn metrics() {
+        use std::sync::{Arc, Mutex};
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        {
+            let records = records.clone();
+            let mut client = AssuanClient::new("OK\nOK\nERR 99 Cancelled\n".as_bytes(), Vec::new()).unwrap();
+            client.set_metrics(move |command: &str, _, outcome| {
+                records.lock().unwrap().push((command.to_owned(), outcome))
+            });
+            client.exec("SETDESC", &[b"secret"]).unwrap();
+            assert!(client.exec("GETPIN", &[]).is_err());
+        }
+        assert_eq!(*records.lock().unwrap(), [("SETDESC".to_owned(), Outcome::Ok),
+                                              ("GETPIN".to_owned(), Outcome::Server(99)),
+                                              ("BYE".to_owned(), Outcome::Failed)]);
+    }
+}
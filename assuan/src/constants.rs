@@ -0,0 +1,97 @@
+//! Keywords and error codes used by GnuPG Assuan servers
+
+/// Error codes from libgpg-error
+///
+/// The code sent in an `ERR` line also carries the error source in its
+/// upper bits, the constants below are for the lower 16 bits e.g.
+///
+///     # use assuan::constants::error;
+///     let err = 83886179u32;
+///     assert_eq!(err & error::CODE_MASK, error::CANCELED);
+///     assert_eq!(err >> error::SOURCE_SHIFT, error::SOURCE_PINENTRY);
+pub mod error {
+    /// Mask for the error code part
+    pub const CODE_MASK: u32 = 0xffff;
+    /// Shift for the error source part
+    pub const SOURCE_SHIFT: u32 = 24;
+
+    pub const NO_ERROR: u32 = 0;
+    pub const GENERAL: u32 = 1;
+    pub const BAD_PASSPHRASE: u32 = 11;
+    pub const NO_SECKEY: u32 = 17;
+    pub const NOT_FOUND: u32 = 27;
+    pub const INV_ARG: u32 = 45;
+    pub const INV_VALUE: u32 = 55;
+    pub const NO_DATA: u32 = 58;
+    pub const NOT_SUPPORTED: u32 = 60;
+    pub const TIMEOUT: u32 = 62;
+    pub const TOO_LARGE: u32 = 67;
+    pub const NO_OBJ: u32 = 68;
+    pub const NOT_IMPLEMENTED: u32 = 69;
+    pub const NO_PIN_ENTRY: u32 = 85;
+    pub const BAD_PIN: u32 = 87;
+    pub const WRONG_CARD: u32 = 91;
+    pub const LINE_TOO_LONG: u32 = 97;
+    pub const CANCELED: u32 = 99;
+    pub const CARD_REMOVED: u32 = 110;
+    pub const INV_CARD: u32 = 111;
+    pub const CARD_NOT_PRESENT: u32 = 112;
+    pub const NOT_CONFIRMED: u32 = 114;
+    pub const INV_ID: u32 = 118;
+    pub const NO_SCDAEMON: u32 = 119;
+    pub const PIN_BLOCKED: u32 = 130;
+    pub const USE_CONDITIONS: u32 = 131;
+    pub const UNKNOWN_OPTION: u32 = 174;
+    pub const UNKNOWN_COMMAND: u32 = 175;
+    pub const NOT_OPERATIONAL: u32 = 176;
+    pub const NO_PASSPHRASE: u32 = 177;
+    pub const NOT_ENABLED: u32 = 179;
+    pub const FULLY_CANCELED: u32 = 198;
+    pub const FORBIDDEN: u32 = 251;
+    pub const FALSE: u32 = 256;
+    pub const ASS_INV_VALUE: u32 = 261;
+    pub const ASS_LINE_TOO_LONG: u32 = 263;
+    pub const ASS_TOO_MUCH_DATA: u32 = 273;
+    pub const ASS_UNKNOWN_CMD: u32 = 275;
+    pub const ASS_SYNTAX: u32 = 276;
+    pub const ASS_CANCELED: u32 = 277;
+    pub const ASS_PARAMETER: u32 = 280;
+    pub const ASS_UNKNOWN_INQUIRE: u32 = 281;
+    pub const EOF: u32 = 16383;
+    pub const EACCES: u32 = 32769;
+    pub const ECONNREFUSED: u32 = 32793;
+    pub const ENODEV: u32 = 32848;
+
+    pub const SOURCE_UNKNOWN: u32 = 0;
+    pub const SOURCE_GCRYPT: u32 = 1;
+    pub const SOURCE_GPG: u32 = 2;
+    pub const SOURCE_GPGSM: u32 = 3;
+    pub const SOURCE_GPGAGENT: u32 = 4;
+    pub const SOURCE_PINENTRY: u32 = 5;
+    pub const SOURCE_SCD: u32 = 6;
+    pub const SOURCE_GPGME: u32 = 7;
+    pub const SOURCE_KEYBOX: u32 = 8;
+    pub const SOURCE_KSBA: u32 = 9;
+    pub const SOURCE_DIRMNGR: u32 = 10;
+    pub const SOURCE_ASSUAN: u32 = 15;
+}
+
+/// Keywords of status (`S`) lines
+pub mod status {
+    pub const PROGRESS: &str = "PROGRESS";
+    pub const INQUIRE_MAXLEN: &str = "INQUIRE_MAXLEN";
+    pub const PINENTRY_LAUNCHED: &str = "PINENTRY_LAUNCHED";
+    pub const KEYINFO: &str = "KEYINFO";
+    pub const SERIALNO: &str = "SERIALNO";
+    pub const CACHE_NONCE: &str = "CACHE_NONCE";
+    pub const PASSWD_NONCE: &str = "PASSWD_NONCE";
+    pub const EVENTCOUNTER: &str = "EVENTCOUNTER";
+    pub const PADDING: &str = "PADDING";
+    pub const KEYPAIRINFO: &str = "KEYPAIRINFO";
+    pub const KEY_FPR: &str = "KEY-FPR";
+    pub const KEY_CREATED_AT: &str = "KEY-CREATED-AT";
+    pub const KEY_DATA: &str = "KEY-DATA";
+    pub const APPTYPE: &str = "APPTYPE";
+    pub const SIG_COUNTER: &str = "SIG-COUNTER";
+    pub const CHV_STATUS: &str = "CHV-STATUS";
+}
@@ -0,0 +1,163 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::fmt;
+#[cfg(feature = "std")]
+use std::error::Error as StdError;
+#[cfg(feature = "std")]
+use std::io::{Error as IoError, ErrorKind};
+
+use constants::error;
+
+pub enum AssuanError {
+    #[cfg(feature = "std")]
+    IoError(IoError),
+    /// The server replied with ERR
+    Server {
+        /// The gpg-error code
+        code: u32,
+        /// The error text, unescaped. Invalid UTF-8 is replaced.
+        description: String,
+        /// The ERR line as received, without the trailing newline
+        line: Vec<u8>,
+    },
+    /// The server sent a line that is not valid Assuan
+    UnexpectedResponse(Vec<u8>),
+    /// The server did not reply in time, see `AssuanClient::exec_timeout()`.
+    /// The reply may still arrive later, so the connection should not be
+    /// used anymore.
+    Timeout,
+    /// The data of the reply exceeds the limit set with
+    /// `AssuanClient::set_max_response_size()`. The rest of the reply is
+    /// not read, so the connection should not be used anymore.
+    ResponseTooLarge(usize),
+    /// The command name given to `exec()` is empty or has spaces or
+    /// control characters, nothing was sent
+    InvalidCommand(String),
+    Other(String),
+}
+
+impl AssuanError {
+    /// The line received from the server that caused this error, if any.
+    pub fn raw_line(&self) -> Option<&[u8]> {
+        match *self {
+            AssuanError::Server { ref line, .. } => Some(line),
+            AssuanError::UnexpectedResponse(ref line) => Some(line),
+            _ => None,
+        }
+    }
+
+    /// A server error, e.g. to reply with ERR from an
+    /// `AssuanServer` command handler.
+    pub fn server<D: Into<String>>(code: u32, description: D) -> Self {
+        let description = description.into();
+        AssuanError::Server {
+            line: format!("ERR {} {}", code, description).into_bytes(),
+            code,
+            description,
+        }
+    }
+
+    /// The gpg-error code sent by the server, with the error source in
+    /// its upper bits.
+    pub fn code(&self) -> Option<u32> {
+        match *self {
+            AssuanError::Server { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    /// The error code part of `code()`, to compare with the constants in
+    /// `constants::error` e.g.
+    ///
+    ///     # use assuan::{AssuanError, constants::error};
+    ///     let err = AssuanError::server(83886179, "Operation cancelled");
+    ///     assert_eq!(err.error_code(), Some(error::CANCELED));
+    ///     assert_eq!(err.error_source(), Some(error::SOURCE_PINENTRY));
+    pub fn error_code(&self) -> Option<u32> {
+        self.code().map(|code| code & error::CODE_MASK)
+    }
+
+    /// The component that raised the error, one of the `SOURCE_` constants
+    /// in `constants::error`.
+    pub fn error_source(&self) -> Option<u32> {
+        self.code().map(|code| code >> error::SOURCE_SHIFT)
+    }
+}
+
+impl fmt::Display for AssuanError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "std")]
+            AssuanError::IoError(ref err) => err.fmt(fmt),
+            AssuanError::Server { code, ref description, .. } => write!(fmt, "{} {}", code, description),
+            AssuanError::UnexpectedResponse(ref line) => {
+                write!(fmt, "Unsupported Assuan response: {}",
+                       String::from_utf8_lossy(line).escape_debug())
+            }
+            AssuanError::Timeout => write!(fmt, "Timed out waiting for the server"),
+            AssuanError::ResponseTooLarge(limit) => write!(fmt, "The server sent more than {} bytes of data", limit),
+            AssuanError::InvalidCommand(ref name) => write!(fmt, "Invalid command name: {}", name.escape_debug()),
+            AssuanError::Other(ref desc) => write!(fmt, "{}", desc),
+        }
+    }
+}
+
+impl fmt::Debug for AssuanError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(feature = "std")]
+            AssuanError::IoError(ref err) => err.fmt(fmt),
+            AssuanError::Server { ref line, .. } | AssuanError::UnexpectedResponse(ref line) => {
+                write!(fmt, "{}", String::from_utf8_lossy(line).escape_debug())
+            }
+            AssuanError::Timeout => write!(fmt, "Timed out waiting for the server"),
+            AssuanError::ResponseTooLarge(limit) => write!(fmt, "The server sent more than {} bytes of data", limit),
+            AssuanError::InvalidCommand(ref name) => write!(fmt, "Invalid command name: {}", name.escape_debug()),
+            AssuanError::Other(ref desc) => write!(fmt, "{}", desc),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl StdError for AssuanError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            AssuanError::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<IoError> for AssuanError {
+    fn from(err: IoError) -> Self {
+        AssuanError::IoError(err)
+    }
+}
+
+/// IO errors are returned as they are, and timeouts as `TimedOut`. Server
+/// errors are mapped by their
+/// gpg-error code to `TimedOut` or `PermissionDenied` where possible, and
+/// to `Other` otherwise.
+#[cfg(feature = "std")]
+impl From<AssuanError> for IoError {
+    fn from(err: AssuanError) -> Self {
+        match err {
+            AssuanError::IoError(err) => err,
+            AssuanError::Server { code, .. } => {
+                let kind = match code & error::CODE_MASK {
+                    error::TIMEOUT => ErrorKind::TimedOut,
+                    error::FORBIDDEN | error::EACCES => ErrorKind::PermissionDenied,
+                    _ => ErrorKind::Other,
+                };
+                IoError::new(kind, err.to_string())
+            }
+            AssuanError::UnexpectedResponse(_) => IoError::new(ErrorKind::InvalidData, err.to_string()),
+            AssuanError::Timeout => IoError::new(ErrorKind::TimedOut, err.to_string()),
+            AssuanError::ResponseTooLarge(_) => IoError::new(ErrorKind::InvalidData, err.to_string()),
+            AssuanError::InvalidCommand(_) => IoError::new(ErrorKind::InvalidInput, err.to_string()),
+            AssuanError::Other(desc) => IoError::other(desc),
+        }
+    }
+}
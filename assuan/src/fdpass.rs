@@ -0,0 +1,37 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+/// Send `fd` over the Unix socket `sock` as SCM_RIGHTS ancillary data.
+/// Like libassuan the data is a comment line, so servers that do not look
+/// for the descriptor only see a comment.
+pub fn send_fd(sock: RawFd, fd: RawFd) -> io::Result<()> {
+    let mut line = format!("# descriptor {} is in flight\n", fd).into_bytes();
+    let mut iov = libc::iovec {
+        iov_base: line.as_mut_ptr() as *mut libc::c_void,
+        iov_len: line.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = vec![0u64; space.div_ceil(mem::size_of::<u64>())];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        if libc::sendmsg(sock, &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,303 @@
+//! The Assuan line format, without any IO
+//!
+//! This only needs `core` and `alloc`, and is available when the crate is
+//! built without the default `std` feature.
+//!
+//!     # use assuan::proto::{encode_command, parse_line, Line};
+//!     assert_eq!(encode_command("SETDESC", &[b"100% sure"]), "SETDESC 100%25%20sure");
+//!     assert_eq!(parse_line(b"S PROGRESS 1 2"),
+//!                Some(Line::Status { keyword: b"PROGRESS", args: b"1 2" }));
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// A line sent by the server, without the trailing newline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Line<'a> {
+    /// `OK [message]`
+    Ok(&'a [u8]),
+    /// `ERR <code> [description]`
+    Err { code: u32, description: &'a [u8] },
+    /// `D <data>`, the data is still escaped
+    Data(&'a [u8]),
+    /// `S <keyword> [args]`
+    Status { keyword: &'a [u8], args: &'a [u8] },
+    /// `INQUIRE <keyword> [args]`
+    Inquire { keyword: &'a [u8], args: &'a [u8] },
+    /// `# comment`
+    Comment(&'a [u8]),
+}
+
+/// Split at the first space, the second part is empty if there is none
+fn split_word(s: &[u8]) -> (&[u8], &[u8]) {
+    match s.iter().position(|&b| b == b' ') {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None => (s, b""),
+    }
+}
+
+/// Parse a server line, the trailing newline should already be removed.
+/// Returns `None` if the line is not valid Assuan.
+pub fn parse_line<'a>(line: &'a [u8]) -> Option<Line<'a>> {
+    if let Some(rest) = line.strip_prefix(b"#") {
+        return Some(Line::Comment(rest));
+    }
+
+    let (keyword, rest) = split_word(line);
+    match keyword {
+        b"OK" => Some(Line::Ok(rest)),
+        b"ERR" => {
+            let (code, description) = split_word(rest);
+            core::str::from_utf8(code).ok()
+                .and_then(|code| code.parse().ok())
+                .map(|code| Line::Err { code, description })
+        }
+        b"D" if line.len() > 1 => Some(Line::Data(rest)),
+        b"S" if !rest.is_empty() => {
+            let (keyword, args) = split_word(rest);
+            Some(Line::Status { keyword, args })
+        }
+        b"INQUIRE" if !rest.is_empty() => {
+            let (keyword, args) = split_word(rest);
+            Some(Line::Inquire { keyword, args })
+        }
+        _ => None,
+    }
+}
+
+/// Maximum length of a line, including the trailing newline
+pub const LINE_LENGTH: usize = 1000;
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Bytes that are percent escaped in arguments. Non ASCII bytes are
+/// escaped so arguments need not be UTF-8, control characters because
+/// servers may cut lines at a NUL, and '+' because some commands decode
+/// it as a space.
+fn needs_escape(byte: u8) -> bool {
+    [b'%', b' ', b'+'].contains(&byte) || !byte.is_ascii() || byte.is_ascii_control()
+}
+
+/// Append `arg` to `out`, percent escaped
+pub(crate) fn escape_arg_into(arg: &[u8], out: &mut String) {
+    for &b in arg {
+        if needs_escape(b) {
+            out.push('%');
+            out.push(HEX[(b >> 4) as usize] as char);
+            out.push(HEX[(b & 0xf) as usize] as char);
+        } else {
+            out.push(b as char);
+        }
+    }
+}
+
+/// Escape a command argument, the result is always ASCII.
+///
+///     assert_eq!(assuan::escape_arg("1 + 1\n".as_bytes()), "1%20%2B%201%0A");
+pub fn escape_arg(arg: &[u8]) -> String {
+    let mut out = String::with_capacity(arg.len());
+    escape_arg_into(arg, &mut out);
+    out
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode the `%XX` escapes in data or arguments sent by the server. A `%`
+/// that is not followed by two hex digits is kept as it is.
+///
+///     assert_eq!(assuan::unescape_data(b"100%25%0Adone"), b"100%\ndone");
+pub fn unescape_data(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'%' && i + 2 < data.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(data[i + 1]), hex_value(data[i + 2])) {
+                out.push(hi << 4 | lo);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(data[i]);
+        i += 1;
+    }
+    out
+}
+
+/// The length of `unescape_data(data)`, without decoding it, used by the
+/// `ReplyParser` data limit
+pub(crate) fn unescaped_len(data: &[u8]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'%' && i + 2 < data.len()
+            && hex_value(data[i + 1]).is_some() && hex_value(data[i + 2]).is_some() {
+            i += 3;
+        } else {
+            i += 1;
+        }
+        len += 1;
+    }
+    len
+}
+
+/// Append `text` to `out` with `%`, CR and LF escaped, as in data, status
+/// and error lines
+pub(crate) fn escape_text_into(text: &[u8], out: &mut Vec<u8>) {
+    for &b in text {
+        if b == b'%' || b == b'\r' || b == b'\n' {
+            out.push(b'%');
+            out.push(HEX[(b >> 4) as usize]);
+            out.push(HEX[(b & 0xf) as usize]);
+        } else {
+            out.push(b);
+        }
+    }
+}
+
+/// Build a `D` line for `data`, with the trailing newline. Only `%`, CR
+/// and LF are escaped. Lines are limited to `LINE_LENGTH` bytes, so
+/// larger data must be split over several lines.
+///
+///     assert_eq!(assuan::escape_data_line(b"50%\n"), b"D 50%25%0A\n");
+pub fn escape_data_line(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 3);
+    out.extend_from_slice(b"D ");
+    escape_text_into(data, &mut out);
+    out.push(b'\n');
+    out
+}
+
+/// Build as many `D` lines as needed to send `data`, each within
+/// `LINE_LENGTH` bytes and with its trailing newline. Empty data gives no
+/// lines.
+///
+///     let lines = assuan::escape_data_lines(&[b'%'; 400]);
+///     assert_eq!(lines.split(|&b| b == b'\n').filter(|line| !line.is_empty()).count(), 2);
+pub fn escape_data_lines(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 300 * 3 + 3);
+    let mut start = out.len();
+    for &b in data {
+        let width = if b == b'%' || b == b'\r' || b == b'\n' { 3 } else { 1 };
+        // Keep room for the newline
+        if out.len() == start || out.len() - start + width >= LINE_LENGTH {
+            if out.len() != start {
+                out.push(b'\n');
+            }
+            start = out.len();
+            out.extend_from_slice(b"D ");
+        }
+        escape_text_into(&[b], &mut out);
+    }
+    if out.len() != start {
+        out.push(b'\n');
+    }
+    out
+}
+
+/// Build a command line with escaped arguments, without the trailing
+/// newline.
+pub fn encode_command(name: &str, args: &[&[u8]]) -> String {
+    let mut cmd = String::from(name);
+    for arg in args {
+        cmd.push(' ');
+        escape_arg_into(arg, &mut cmd);
+    }
+    cmd
+}
+
+/// Split command arguments at spaces and unescape them, the reverse of
+/// `encode_command()`.
+///
+///     # use assuan::proto::split_args;
+///     assert_eq!(split_args(b"100%25  sure%0A"), [&b"100%"[..], b"sure\n"]);
+pub fn split_args(args: &[u8]) -> Vec<Vec<u8>> {
+    args.split(|&b| b == b' ')
+        .filter(|arg| !arg.is_empty())
+        .map(unescape_data)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(parse_line(b"OK"), Some(Line::Ok(b"")));
+        assert_eq!(parse_line(b"OK Pleased to meet you"), Some(Line::Ok(b"Pleased to meet you")));
+        assert_eq!(parse_line(b"ERR 83886179 Operation cancelled"),
+                   Some(Line::Err { code: 83886179, description: b"Operation cancelled" }));
+        assert_eq!(parse_line(b"D 2.2.40"), Some(Line::Data(b"2.2.40")));
+        assert_eq!(parse_line(b"S EVENTCOUNTER 1 2 3"),
+                   Some(Line::Status { keyword: b"EVENTCOUNTER", args: b"1 2 3" }));
+        assert_eq!(parse_line(b"INQUIRE PINENTRY_LAUNCHED 1234"),
+                   Some(Line::Inquire { keyword: b"PINENTRY_LAUNCHED", args: b"1234" }));
+        assert_eq!(parse_line(b"# comment"), Some(Line::Comment(b" comment")));
+
+        assert_eq!(parse_line(b"ERR cancelled"), None);
+        assert_eq!(parse_line(b"OKAY"), None);
+        assert_eq!(parse_line(b"S"), None);
+        assert_eq!(parse_line(b""), None);
+    }
+
+    #[test]
+    fn unescape_invalid() {
+        assert_eq!(unescape_data(b"%"), b"%");
+        assert_eq!(unescape_data(b"%4"), b"%4");
+        assert_eq!(unescape_data(b"%zz%41"), b"%zzA");
+        assert_eq!(unescape_data(b"%2b+"), b"++");
+    }
+
+    proptest! {
+        #[test]
+        fn arg_round_trip(arg in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let escaped = escape_arg(&arg);
+            prop_assert!(escaped.bytes().all(|b| !needs_escape(b) || b == b'%'));
+            prop_assert_eq!(unescape_data(escaped.as_bytes()), arg);
+        }
+
+        #[test]
+        fn command_round_trip(args in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 1..32), 0..8)) {
+            let refs: Vec<&[u8]> = args.iter().map(|arg| &arg[..]).collect();
+            let command = encode_command("CMD", &refs);
+            prop_assert_eq!(split_args(command.as_bytes()[3..].as_ref()), args);
+        }
+
+        #[test]
+        fn data_lines_round_trip(data in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            let lines = escape_data_lines(&data);
+            let mut decoded = Vec::new();
+            for line in lines.split_inclusive(|&b| b == b'\n') {
+                prop_assert!(line.len() <= LINE_LENGTH && line.ends_with(b"\n"));
+                match parse_line(&line[..line.len() - 1]) {
+                    Some(Line::Data(escaped)) => decoded.extend(unescape_data(escaped)),
+                    other => prop_assert!(false, "{:?}", other),
+                }
+            }
+            prop_assert_eq!(decoded, data);
+        }
+
+        #[test]
+        fn unescaped_length(data in proptest::collection::vec(prop_oneof![Just(b'%'), Just(b'a'), Just(b'4'), any::<u8>()], 0..64)) {
+            prop_assert_eq!(unescaped_len(&data), unescape_data(&data).len());
+        }
+
+        #[test]
+        fn data_round_trip(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let line = escape_data_line(&data);
+            prop_assert!(!line[..line.len() - 1].contains(&b'\n'));
+            match parse_line(&line[..line.len() - 1]) {
+                Some(Line::Data(escaped)) => prop_assert_eq!(unescape_data(escaped), data),
+                other => prop_assert!(false, "{:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use std::io::{self, Write, BufReader, BufRead, Read};
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use AssuanError;
+
+/// The response sent to the client when a command is not allowed,
+/// the same error the gpg-agent restricted socket replies with
+/// (GPG_ERR_FORBIDDEN from gpg-agent).
+const FORBIDDEN: &[u8] = b"ERR 67109115 Forbidden\n";
+
+/// Commands whose arguments and OK response carry secrets, these are
+/// redacted in transcripts.
+const SENSITIVE: &[&str] = &["GET_PASSPHRASE", "PRESET_PASSPHRASE", "PUT_SECRET"];
+
+/// Forwards an Assuan session between a client and a server, optionally
+/// restricting the commands the client is allowed to issue
+///
+/// Commands that are not allowed are never seen by the server, the
+/// client gets an `ERR` response instead. Data sent by the client in
+/// reply to an `INQUIRE` is always forwarded, and so is `BYE` so the
+/// session can be terminated cleanly. Any other line sent during an
+/// `INQUIRE` cancels it, the server never sees that line.
+///
+///     # use assuan::AssuanProxy;
+///     let proxy = AssuanProxy::new()
+///                 .allow("GETINFO")
+///                 .allow("GET_PASSPHRASE");
+///     assert!(proxy.is_allowed("getinfo"));
+///     assert!(!proxy.is_allowed("PRESET_PASSPHRASE"));
+#[derive(Clone, Debug, Default)]
+pub struct AssuanProxy {
+    allowed: Option<HashSet<String>>,
+}
+
+impl AssuanProxy {
+    /// Creates a proxy that forwards every command.
+    pub fn new() -> AssuanProxy {
+        AssuanProxy::default()
+    }
+
+    /// Add a command to the allow-list. Once a command is added only
+    /// allowed commands are forwarded.
+    pub fn allow(mut self, name: &str) -> AssuanProxy {
+        self.allowed.get_or_insert_with(HashSet::new)
+            .insert(name.to_uppercase());
+        self
+    }
+
+    /// Creates a proxy that only forwards the given commands.
+    pub fn with_allow_list(names: &[&str]) -> AssuanProxy {
+        names.iter().fold(AssuanProxy { allowed: Some(HashSet::new()) },
+                          |proxy, name| proxy.allow(name))
+    }
+
+    /// Check if a command would be forwarded. Command names are case
+    /// insensitive.
+    pub fn is_allowed(&self, name: &str) -> bool {
+        match self.allowed {
+            None => true,
+            Some(ref allowed) => {
+                let name = name.to_uppercase();
+                name == "BYE" || allowed.contains(&name)
+            }
+        }
+    }
+
+    /// Run the proxy until the client disconnects or the session ends
+    /// with `BYE`.
+    ///
+    /// The server greeting is read first and passed on to the client.
+    pub fn run<CR, CW, SR, SW>(&self, client_r: CR, client_w: CW, server_r: SR, server_w: SW)
+        -> Result<(), AssuanError>
+        where CR: Read, CW: Write, SR: Read, SW: Write
+    {
+        self.proxy(client_r, client_w, server_r, server_w, &mut Transcript::none())
+    }
+
+    /// Same as `run()`, but also write a timestamped transcript of both
+    /// directions of the session to `transcript`. Lines are tagged with
+    /// C (client), S (server) or P (rejected by the proxy), e.g.
+    ///
+    /// ```text
+    /// 1697371234.120 S: OK Pleased to meet you
+    /// 1697371234.121 C: GETINFO version
+    /// 1697371234.121 S: D [6 bytes]
+    /// 1697371234.121 S: OK
+    /// ```
+    ///
+    /// Data lines are never logged, and neither are the arguments and
+    /// responses of commands that carry passphrases.
+    pub fn run_with_transcript<CR, CW, SR, SW, T>(&self, client_r: CR, client_w: CW,
+                                                  server_r: SR, server_w: SW, mut transcript: T)
+        -> Result<(), AssuanError>
+        where CR: Read, CW: Write, SR: Read, SW: Write, T: Write
+    {
+        self.proxy(client_r, client_w, server_r, server_w, &mut Transcript::new(&mut transcript))
+    }
+
+    fn proxy<CR, CW, SR, SW>(&self, client_r: CR, mut client_w: CW, server_r: SR, mut server_w: SW,
+                             transcript: &mut Transcript)
+        -> Result<(), AssuanError>
+        where CR: Read, CW: Write, SR: Read, SW: Write
+    {
+        let mut client_r = BufReader::new(client_r);
+        let mut server_r = BufReader::new(server_r);
+
+        forward_response(&mut server_r, &mut client_w, &mut client_r, &mut server_w, transcript)?;
+
+        loop {
+            let mut line = Vec::new();
+            if client_r.read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+
+            let name = command_name(&line);
+            if name.is_empty() || name.starts_with('#') {
+                // Empty and comment lines get no response from the server
+                continue;
+            }
+            transcript.redact = SENSITIVE.iter().any(|cmd| name.eq_ignore_ascii_case(cmd));
+            transcript.client(&line)?;
+            if !self.is_allowed(&name) {
+                transcript.proxy(FORBIDDEN)?;
+                client_w.write_all(FORBIDDEN)?;
+                client_w.flush()?;
+                continue;
+            }
+
+            server_w.write_all(&line)?;
+            server_w.flush()?;
+            forward_response(&mut server_r, &mut client_w, &mut client_r, &mut server_w, transcript)?;
+
+            if name.eq_ignore_ascii_case("BYE") {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Writes the lines exchanged in a proxied session
+struct Transcript<'a> {
+    out: Option<&'a mut dyn Write>,
+    /// Hide the arguments of the current command and its OK response
+    redact: bool,
+}
+
+impl<'a> Transcript<'a> {
+    fn new(out: &'a mut dyn Write) -> Transcript<'a> {
+        Transcript { out: Some(out), redact: false }
+    }
+
+    fn none() -> Transcript<'a> {
+        Transcript { out: None, redact: false }
+    }
+
+    fn client(&mut self, line: &[u8]) -> io::Result<()> {
+        self.write("C", line)
+    }
+
+    fn server(&mut self, line: &[u8]) -> io::Result<()> {
+        self.write("S", line)
+    }
+
+    /// Lines the proxy answers itself
+    fn proxy(&mut self, line: &[u8]) -> io::Result<()> {
+        self.write("P", line)
+    }
+
+    fn write(&mut self, from: &str, line: &[u8]) -> io::Result<()> {
+        let out = match self.out {
+            Some(ref mut out) => out,
+            None => return Ok(()),
+        };
+
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\n', '\r']);
+        let shown = if let Some(data) = line.strip_prefix("D ") {
+            format!("D [{} bytes]", data.len())
+        } else if self.redact && (from == "C" || line.starts_with("OK")) {
+            match line.find(' ') {
+                Some(idx) => format!("{} [redacted]", &line[..idx]),
+                None => line.to_owned(),
+            }
+        } else {
+            line.to_owned()
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(out, "{}.{:03} {}: {}", now.as_secs(), now.subsec_millis(), from, shown)?;
+        out.flush()
+    }
+}
+
+/// Extract the command name from a client line
+fn command_name(line: &[u8]) -> String {
+    let name = line.split(|b| *b == b' ' || *b == b'\n' || *b == b'\r')
+        .next()
+        .unwrap_or(&[]);
+    String::from_utf8_lossy(name).into_owned()
+}
+
+/// Forward server lines to the client until the final OK or ERR. If the
+/// server INQUIREs, the client reply is forwarded up to END or CAN, see
+/// `forward_inquiry()`.
+fn forward_response<SR, CW, CR, SW>(server_r: &mut SR, client_w: &mut CW, client_r: &mut CR, server_w: &mut SW,
+                                    transcript: &mut Transcript)
+    -> Result<(), AssuanError>
+    where SR: BufRead, CW: Write, CR: BufRead, SW: Write
+{
+    loop {
+        let mut line = Vec::new();
+        if server_r.read_until(b'\n', &mut line)? == 0 {
+            return Err(AssuanError::Other("Server closed the connection".to_owned()));
+        }
+        transcript.server(&line)?;
+        client_w.write_all(&line)?;
+        client_w.flush()?;
+
+        if line.starts_with(b"OK") || line.starts_with(b"ERR") {
+            return Ok(());
+        } else if line.starts_with(b"INQUIRE") {
+            forward_inquiry(client_r, server_w, transcript)?;
+        }
+    }
+}
+
+/// Forward the D lines sent by the client to answer an INQUIRE, up to an
+/// exact END or CAN. Any other line cancels the inquiry.
+fn forward_inquiry<CR, SW>(client_r: &mut CR, server_w: &mut SW, transcript: &mut Transcript)
+    -> Result<(), AssuanError>
+    where CR: BufRead, SW: Write
+{
+    loop {
+        let mut line = Vec::new();
+        if client_r.read_until(b'\n', &mut line)? == 0 {
+            return Err(AssuanError::Other("Client closed the connection".to_owned()));
+        }
+        transcript.client(&line)?;
+
+        let keyword = command_name(&line);
+        let trimmed = line.strip_suffix(b"\n").unwrap_or(&line);
+        let trimmed = trimmed.strip_suffix(b"\r").unwrap_or(trimmed);
+        if keyword.starts_with('#') {
+            // Comments get no response, the server does not need them
+            continue;
+        } else if keyword == "D" {
+            server_w.write_all(&line)?;
+        } else if trimmed == b"END" || trimmed == b"CAN" {
+            server_w.write_all(&line)?;
+            server_w.flush()?;
+            return Ok(());
+        } else {
+            // The server would fail the inquiry and go back to reading
+            // commands, which the proxy has not checked. Cancel it instead,
+            // the client gets the ERR for its command.
+            transcript.proxy(b"CAN\n")?;
+            server_w.write_all(b"CAN\n")?;
+            server_w.flush()?;
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(proxy: &AssuanProxy, client: &str, server: &str) -> (String, String) {
+        let mut to_client = Vec::new();
+        let mut to_server = Vec::new();
+        proxy.run(client.as_bytes(), &mut to_client, server.as_bytes(), &mut to_server)
+            .unwrap();
+        (String::from_utf8(to_client).unwrap(), String::from_utf8(to_server).unwrap())
+    }
+
+    #[test]
+    fn forwards_allowed_and_rejects_others() {
+        let proxy = AssuanProxy::with_allow_list(&["GETINFO"]);
+        let (to_client, to_server) = run(&proxy,
+                                         "getinfo version\nKILLAGENT\nBYE\n",
+                                         "OK hello\nD 2.2.40\nOK\nOK closing\n");
+        assert_eq!(to_server, "getinfo version\nBYE\n");
+        assert_eq!(to_client,
+                   "OK hello\nD 2.2.40\nOK\nERR 67109115 Forbidden\nOK closing\n");
+    }
+
+    #[test]
+    fn forwards_inquiry_data() {
+        let proxy = AssuanProxy::new();
+        let (to_client, to_server) = run(&proxy,
+                                         "PKDECRYPT\nD abc\nEND\n",
+                                         "OK\nINQUIRE CIPHERTEXT\nD plain\nOK\n");
+        assert_eq!(to_server, "PKDECRYPT\nD abc\nEND\n");
+        assert_eq!(to_client, "OK\nINQUIRE CIPHERTEXT\nD plain\nOK\n");
+    }
+
+    #[test]
+    fn other_lines_cancel_inquiry() {
+        let proxy = AssuanProxy::with_allow_list(&["PRESET_PASSPHRASE"]);
+        let (to_client, to_server) = run(&proxy,
+                                         "PRESET_PASSPHRASE --inquire grip -1\n# note\nD x\nBOGUS\nKILLAGENT\nEND\n",
+                                         "OK\nINQUIRE PASSPHRASE\nERR 83886179 Operation cancelled\n");
+        assert_eq!(to_server, "PRESET_PASSPHRASE --inquire grip -1\nD x\nCAN\n");
+        assert_eq!(to_client, "OK\nINQUIRE PASSPHRASE\nERR 83886179 Operation cancelled\n\
+                               ERR 67109115 Forbidden\nERR 67109115 Forbidden\n");
+
+        // END must be the whole line
+        let (_, to_server) = run(&proxy, "PRESET_PASSPHRASE --inquire grip -1\nEND now\n",
+                                 "OK\nINQUIRE PASSPHRASE\nERR 83886179 Operation cancelled\n");
+        assert_eq!(to_server, "PRESET_PASSPHRASE --inquire grip -1\nCAN\n");
+    }
+
+    #[test]
+    fn transcript_is_redacted() {
+        let proxy = AssuanProxy::new();
+        let mut transcript = Vec::new();
+        proxy.run_with_transcript("GET_PASSPHRASE id X X desc\nGETINFO version\n".as_bytes(),
+                                  io::sink(),
+                                  "OK\nOK 736563726574\nD 2.2.40\nOK\n".as_bytes(),
+                                  io::sink(),
+                                  &mut transcript)
+            .unwrap();
+
+        let transcript = String::from_utf8(transcript).unwrap();
+        let lines: Vec<_> = transcript.lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lines, ["S: OK",
+                           "C: GET_PASSPHRASE [redacted]",
+                           "S: OK [redacted]",
+                           "C: GETINFO version",
+                           "S: D [6 bytes]",
+                           "S: OK"]);
+    }
+}
@@ -0,0 +1,217 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::str;
+
+use error::AssuanError;
+use proto::{self, Line};
+
+
+/// The reply to a command that succeeded, errors are returned as
+/// `AssuanError::Server`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Response {
+    /// The text after OK, unescaped. Invalid UTF-8 is replaced.
+    pub message: String,
+    /// The data sent in D lines, unescaped
+    pub data: Vec<u8>,
+    /// The status lines (keyword, arguments) sent during the command. The
+    /// arguments are still escaped, see `InquiryHandler`.
+    pub status: Vec<(String, String)>,
+    /// The text of the comment lines, e.g. the output of `HELP`
+    pub comments: Vec<String>,
+}
+
+impl Response {
+    /// The arguments of the status lines with this keyword, in the order
+    /// they were sent.
+    pub fn status<'a>(&'a self, keyword: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.status.iter()
+            .filter(move |&(kw, _)| kw == keyword)
+            .map(|(_, args)| args.as_str())
+    }
+}
+
+/// What the server sent, see `ReplyParser::feed()`
+#[derive(Debug)]
+pub enum ReplyEvent {
+    /// A status line, also kept in the `Response`. `args` is still escaped.
+    Status { keyword: String, args: String },
+    /// The server waits for an answer to `INQUIRE keyword args`, with
+    /// `args` still escaped. Send the D lines and END, or CAN, then keep
+    /// feeding lines.
+    Inquire { keyword: String, args: String },
+    /// The reply is complete, with OK or ERR
+    Done(Result<Response, AssuanError>),
+}
+
+/// Assembles the reply to a command from the lines the server sends,
+/// without doing any IO
+///
+/// This is the state machine behind `AssuanClient`. Clients on other
+/// transports, e.g. async ones, read the lines themselves and only need
+/// to answer inquiries:
+///
+///     # use assuan::{ReplyEvent, ReplyParser};
+///     let mut parser = ReplyParser::new();
+///     for line in ["S PROGRESS 1", "D 100%25", "OK done"] {
+///         match parser.feed(line.as_bytes()) {
+///             Some(ReplyEvent::Done(res)) => assert_eq!(res.unwrap().data, b"100%"),
+///             Some(ReplyEvent::Inquire { .. }) => unreachable!("send CAN or the data"),
+///             Some(ReplyEvent::Status { .. }) | None => (),
+///         }
+///     }
+#[derive(Clone, Debug, Default)]
+pub struct ReplyParser {
+    // D lines are appended as they are, and unescaped at the end
+    data: Vec<u8>,
+    // The length of the data once unescaped
+    data_len: usize,
+    max_data: Option<usize>,
+    status: Vec<(String, String)>,
+    comments: Vec<String>,
+}
+
+impl ReplyParser {
+    /// A parser for the reply to one command
+    pub fn new() -> Self {
+        ReplyParser::default()
+    }
+
+    /// Fail with `AssuanError::ResponseTooLarge` once the unescaped data
+    /// exceeds `limit` bytes.
+    pub fn max_data(mut self, limit: usize) -> Self {
+        self.max_data = Some(limit);
+        self
+    }
+
+    /// Feed the next line sent by the server, with or without its
+    /// newline. Returns `None` when the line needs no action.
+    pub fn feed(&mut self, line: &[u8]) -> Option<ReplyEvent> {
+        // With the exception of the trailing NL, the output
+        // should have no NL bytes (they are escaped as %0A)
+        let resp = line.strip_suffix(b"\n").unwrap_or(line);
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+
+        match proto::parse_line(resp) {
+            Some(Line::Data(rest)) => {
+                self.data_len += proto::unescaped_len(rest);
+                match self.max_data {
+                    Some(limit) if self.data_len > limit => {
+                        return Some(ReplyEvent::Done(Err(AssuanError::ResponseTooLarge(limit))));
+                    }
+                    _ => self.data.extend_from_slice(rest),
+                }
+            }
+            // The description may be localized, and escaped
+            Some(Line::Err { code, description }) => {
+                return Some(ReplyEvent::Done(Err(AssuanError::Server {
+                    code,
+                    description: text(&proto::unescape_data(description)),
+                    line: resp.to_vec(),
+                })));
+            }
+            _ if str::from_utf8(resp).is_err() => {
+                return Some(ReplyEvent::Done(Err(AssuanError::UnexpectedResponse(resp.to_vec()))));
+            }
+            Some(Line::Ok(rest)) => {
+                return Some(ReplyEvent::Done(Ok(Response {
+                    message: text(&proto::unescape_data(rest)),
+                    data: proto::unescape_data(&self.data),
+                    status: self.status.clone(),
+                    comments: self.comments.clone(),
+                })));
+            }
+            Some(Line::Status { keyword, args }) => {
+                self.status.push((text(keyword), text(args)));
+                return Some(ReplyEvent::Status { keyword: text(keyword), args: text(args) });
+            }
+            Some(Line::Inquire { keyword, args }) => {
+                return Some(ReplyEvent::Inquire { keyword: text(keyword), args: text(args) });
+            }
+            Some(Line::Comment(rest)) => {
+                let rest = rest.strip_prefix(b" ").unwrap_or(rest);
+                self.comments.push(text(rest));
+            }
+            None => return Some(ReplyEvent::Done(Err(AssuanError::UnexpectedResponse(resp.to_vec())))),
+        }
+        None
+    }
+
+    /// The status lines received so far
+    pub fn status_lines(&self) -> &[(String, String)] {
+        &self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn events() {
+        let mut parser = ReplyParser::new();
+        assert!(parser.feed(b"# comment\n").is_none());
+        assert!(parser.feed(b"D a%0A\n").is_none());
+        match parser.feed(b"S KEYINFO x y\n") {
+            Some(ReplyEvent::Status { keyword, args }) => assert_eq!((keyword.as_str(), args.as_str()), ("KEYINFO", "x y")),
+            event => panic!("{:?}", event),
+        }
+        match parser.feed(b"INQUIRE PASSPHRASE") {
+            Some(ReplyEvent::Inquire { keyword, args }) => assert_eq!((keyword.as_str(), args.as_str()), ("PASSPHRASE", "")),
+            event => panic!("{:?}", event),
+        }
+        match parser.feed(b"D b") {
+            None => (),
+            event => panic!("{:?}", event),
+        }
+        match parser.feed(b"OK") {
+            Some(ReplyEvent::Done(Ok(res))) => {
+                assert_eq!(res.data, b"a\nb");
+                assert_eq!(res.comments, ["comment"]);
+                assert_eq!(res.status, parser.status_lines());
+            }
+            event => panic!("{:?}", event),
+        }
+
+        match ReplyParser::new().feed(b"OK 100%25%0Adone") {
+            Some(ReplyEvent::Done(Ok(res))) => assert_eq!(res.message, "100%\ndone"),
+            event => panic!("{:?}", event),
+        }
+        match ReplyParser::new().feed(b"ERR 99 Canceled%0A") {
+            Some(ReplyEvent::Done(Err(err))) => {
+                assert_eq!(err.code(), Some(99));
+                assert_eq!(err.to_string(), "99 Canceled\n");
+            }
+            event => panic!("{:?}", event),
+        }
+        // Lone % are counted as they are, not as escapes
+        let mut parser = ReplyParser::new().max_data(8);
+        for line in [&b"D %"[..], b"D %4", b"D 100%", b"D %41"] {
+            assert!(parser.feed(line).is_none());
+        }
+        match parser.feed(b"D x") {
+            Some(ReplyEvent::Done(Err(AssuanError::ResponseTooLarge(8)))) => (),
+            event => panic!("{:?}", event),
+        }
+
+        // Raw lines are kept without their newline
+        match ReplyParser::new().feed(b"OK \xff\n") {
+            Some(ReplyEvent::Done(Err(AssuanError::UnexpectedResponse(line)))) => assert_eq!(line, b"OK \xff"),
+            event => panic!("{:?}", event),
+        }
+        match ReplyParser::new().feed(b"ERR 99 No\n") {
+            Some(ReplyEvent::Done(Err(err))) => assert_eq!(err.raw_line(), Some(&b"ERR 99 No"[..])),
+            event => panic!("{:?}", event),
+        }
+        match ReplyParser::new().feed(b"HELLO\n") {
+            Some(ReplyEvent::Done(Err(AssuanError::UnexpectedResponse(line)))) => assert_eq!(line, b"HELLO"),
+            event => panic!("{:?}", event),
+        }
+
+        match ReplyParser::new().feed(b"HELLO") {
+            Some(ReplyEvent::Done(Err(AssuanError::UnexpectedResponse(line)))) => assert_eq!(line, b"HELLO"),
+            event => panic!("{:?}", event),
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::process;
+use std::str;
+
+use constants::error;
+use proto::{self, Line};
+use AssuanError;
+
+/// An error code of the server itself, with the Assuan error source
+fn assuan_code(code: u32) -> u32 {
+    error::SOURCE_ASSUAN << error::SOURCE_SHIFT | code
+}
+
+/// Handles the commands of an `AssuanServer`
+///
+/// `OPTION`, `RESET`, `NOP` and `BYE` are answered by the server, after
+/// calling `option()` and `reset()` for the first two. So are `GETINFO pid`
+/// and, once set with `AssuanServer::version()`, `GETINFO version`. Every
+/// other command is passed to `command()`.
+pub trait CommandHandler {
+    /// Run `command`, given in upper case. `args` is the rest of the line
+    /// as sent, still escaped, see `proto::split_args()` and
+    /// `unescape_data()`.
+    ///
+    /// Data and status lines are sent through `responder`. The command
+    /// then ends with OK, or with ERR if an error is returned: an
+    /// `AssuanError::Server` sends its code and description, IO errors end
+    /// the session and other errors are sent as `GPG_ERR_GENERAL`.
+    fn command(&mut self, responder: &mut Responder, command: &str, args: &[u8]) -> Result<(), AssuanError>;
+
+    /// Set an option sent with `OPTION name=value`, `OPTION name value` or
+    /// `OPTION name`, the value is unescaped. All options are accepted by
+    /// default.
+    fn option(&mut self, _name: &str, _value: &str) -> Result<(), AssuanError> {
+        Ok(())
+    }
+
+    /// Forget the state of the session on `RESET`, e.g. the options.
+    fn reset(&mut self) {}
+}
+
+/// Sends data, status lines and inquiries to the client while a command
+/// runs
+pub struct Responder<'a> {
+    r: &'a mut dyn BufRead,
+    w: &'a mut dyn Write,
+}
+
+impl<'a> Responder<'a> {
+    /// Send `data` in as many D lines as needed.
+    pub fn data(&mut self, data: &[u8]) -> Result<(), AssuanError> {
+        self.w.write_all(&proto::escape_data_lines(data))?;
+        Ok(())
+    }
+
+    /// Send a status line, e.g. `S PROGRESS`.
+    pub fn status(&mut self, keyword: &str, args: &str) -> Result<(), AssuanError> {
+        send_line(self.w, "S", keyword, args)
+    }
+
+    /// Send `INQUIRE keyword args` and return the data the client answers
+    /// with. Fails with `GPG_ERR_ASS_CANCELED` if the client cancels.
+    pub fn inquire(&mut self, keyword: &str, args: &str) -> Result<Vec<u8>, AssuanError> {
+        send_line(self.w, "INQUIRE", keyword, args)?;
+        self.w.flush()?;
+
+        let mut data = Vec::new();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if read_line(self.r, &mut line)? == 0 {
+                return Err(AssuanError::UnexpectedResponse(line));
+            }
+            // The client would go on sending data, end the session
+            if line.len() > proto::LINE_LENGTH {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Line too long").into());
+            }
+            let resp = line.strip_suffix(b"\n").unwrap_or(&line);
+            match resp {
+                b"END" => return Ok(data),
+                b"CAN" => return Err(AssuanError::server(assuan_code(error::ASS_CANCELED),
+                                                         "IPC call has been cancelled")),
+                _ => match proto::parse_line(resp) {
+                    Some(Line::Data(escaped)) => data.extend(proto::unescape_data(escaped)),
+                    Some(Line::Comment(_)) => (),
+                    _ => return Err(AssuanError::UnexpectedResponse(resp.to_vec())),
+                },
+            }
+        }
+    }
+}
+
+/// Read a line, stopping one byte past the line limit so a client that
+/// never sends a newline can not fill the memory
+fn read_line(r: &mut dyn BufRead, line: &mut Vec<u8>) -> io::Result<usize> {
+    r.take(proto::LINE_LENGTH as u64 + 1).read_until(b'\n', line)
+}
+
+/// Discard the rest of a line that is too long
+fn skip_line(r: &mut dyn BufRead) -> io::Result<()> {
+    loop {
+        let (used, done) = {
+            let buf = r.fill_buf()?;
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(pos) => (pos + 1, true),
+                None => (buf.len(), buf.is_empty()),
+            }
+        };
+        r.consume(used);
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Write `<kind> <keyword> [text]`, with the text escaped
+fn send_line(w: &mut dyn Write, kind: &str, keyword: &str, text: &str) -> Result<(), AssuanError> {
+    let mut line = format!("{} {}", kind, keyword).into_bytes();
+    if !text.is_empty() {
+        line.push(b' ');
+        proto::escape_text_into(text.as_bytes(), &mut line);
+    }
+    line.push(b'\n');
+    debug!("> {}", String::from_utf8_lossy(&line));
+    w.write_all(&line)?;
+    Ok(())
+}
+
+/// Assuan server, runs a session with one client e.g. to implement a
+/// pinentry or a fake agent
+///
+///     # use assuan::{AssuanError, AssuanServer, CommandHandler, Responder};
+///     struct Pinentry;
+///
+///     impl CommandHandler for Pinentry {
+///         fn command(&mut self, responder: &mut Responder, command: &str, _: &[u8]) -> Result<(), AssuanError> {
+///             match command {
+///                 "GETPIN" => responder.data(b"1234"),
+///                 _ => Err(AssuanError::server(275, "Unknown command")),
+///             }
+///         }
+///     }
+///
+///     let mut replies = Vec::new();
+///     AssuanServer::new(&b"GETPIN\nBYE\n"[..], &mut replies).serve(&mut Pinentry).unwrap();
+///     assert_eq!(replies, b"OK Pleased to meet you\nD 1234\nOK\nOK closing connection\n");
+pub struct AssuanServer<R, W> where R: Read, W: Write {
+    r: BufReader<R>,
+    w: W,
+    greeting: String,
+    version: Option<String>,
+}
+
+impl<R, W> AssuanServer<R, W> where R: Read, W: Write {
+    /// A server reading commands from `r` and replying on `w`, e.g. stdin
+    /// and stdout for a pinentry.
+    pub fn new(r: R, w: W) -> Self {
+        AssuanServer {
+            r: BufReader::new(r),
+            w,
+            greeting: "Pleased to meet you".to_owned(),
+            version: None,
+        }
+    }
+
+    /// The text of the OK line sent when the client connects.
+    pub fn greeting<G: Into<String>>(mut self, greeting: G) -> Self {
+        self.greeting = greeting.into();
+        self
+    }
+
+    /// The version sent for `GETINFO version`. Until it is set, the command
+    /// goes to the handler.
+    pub fn version<V: Into<String>>(mut self, version: V) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Greet the client and run commands with `handler` until the client
+    /// sends `BYE` or closes the connection.
+    pub fn serve<H: CommandHandler + ?Sized>(&mut self, handler: &mut H) -> Result<(), AssuanError> {
+        self.reply(Ok(()), &self.greeting.clone())?;
+
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if read_line(&mut self.r, &mut line)? == 0 {
+                return Ok(());
+            }
+            debug!("< {}", String::from_utf8_lossy(&line));
+            if line.len() > proto::LINE_LENGTH {
+                if !line.ends_with(b"\n") {
+                    skip_line(&mut self.r)?;
+                }
+                let err = AssuanError::server(assuan_code(error::ASS_LINE_TOO_LONG), "Line too long");
+                self.reply(Err(err), "")?;
+                continue;
+            }
+            let request = line.strip_suffix(b"\n").unwrap_or(&line);
+            let request = request.strip_suffix(b"\r").unwrap_or(request);
+            if request.is_empty() || request.starts_with(b"#") {
+                continue;
+            }
+
+            let (name, args) = match request.iter().position(|&b| b == b' ') {
+                Some(idx) => (&request[..idx], &request[idx + 1..]),
+                None => (request, &b""[..]),
+            };
+            let name = String::from_utf8_lossy(name).to_uppercase();
+            let res = match name.as_str() {
+                "BYE" => return self.reply(Ok(()), "closing connection"),
+                "NOP" => Ok(()),
+                "RESET" => {
+                    handler.reset();
+                    Ok(())
+                }
+                "OPTION" => parse_option(args).and_then(|(name, value)| handler.option(&name, &value)),
+                _ => {
+                    let mut responder = Responder { r: &mut self.r, w: &mut self.w };
+                    match (name.as_str(), args, self.version.as_ref()) {
+                        ("GETINFO", b"pid", _) => responder.data(process::id().to_string().as_bytes()),
+                        ("GETINFO", b"version", Some(version)) => responder.data(version.as_bytes()),
+                        _ => handler.command(&mut responder, &name, args),
+                    }
+                }
+            };
+            self.reply(res, "")?;
+        }
+    }
+
+    /// End a command with OK or ERR
+    fn reply(&mut self, res: Result<(), AssuanError>, message: &str) -> Result<(), AssuanError> {
+        match res {
+            Ok(()) if message.is_empty() => self.w.write_all(b"OK\n")?,
+            Ok(()) => send_line(&mut self.w, "OK", message, "")?,
+            Err(AssuanError::IoError(err)) => return Err(err.into()),
+            Err(AssuanError::Server { code, description, .. }) => {
+                send_line(&mut self.w, "ERR", &code.to_string(), &description)?
+            }
+            Err(err) => send_line(&mut self.w, "ERR", &error::GENERAL.to_string(), &err.to_string())?,
+        }
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+/// Split the arguments of OPTION into the name and the unescaped value
+fn parse_option(args: &[u8]) -> Result<(String, String), AssuanError> {
+    let args = str::from_utf8(args)
+        .map_err(|_| AssuanError::server(assuan_code(error::ASS_INV_VALUE), "Invalid value"))?;
+    let args = args.trim_start_matches("--");
+    let (name, value) = match args.find(['=', ' ']) {
+        Some(idx) => (&args[..idx], args[idx + 1..].trim_start_matches([' ', '='])),
+        None => (args, ""),
+    };
+    if name.is_empty() {
+        return Err(AssuanError::server(assuan_code(error::ASS_SYNTAX), "Syntax error"));
+    }
+    let value = String::from_utf8_lossy(&proto::unescape_data(value.as_bytes())).into_owned();
+    Ok((name.to_owned(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Error as IoError;
+
+    #[derive(Default)]
+    struct Echo {
+        options: Vec<(String, String)>,
+    }
+
+    impl CommandHandler for Echo {
+        fn command(&mut self, responder: &mut Responder, command: &str, args: &[u8]) -> Result<(), AssuanError> {
+            match command {
+                "ECHO" => {
+                    responder.status("PROGRESS", "echo 1 1")?;
+                    responder.data(&proto::split_args(args).concat())
+                }
+                "ASK" => {
+                    let answer = responder.inquire("WHAT", "100%")?;
+                    responder.data(&answer)
+                }
+                "OPTIONS" => {
+                    let options: Vec<String> = self.options.iter().map(|(n, v)| format!("{}={}", n, v)).collect();
+                    responder.data(options.join(",").as_bytes())
+                }
+                _ => Err(AssuanError::server(assuan_code(error::ASS_UNKNOWN_CMD), "Unknown command")),
+            }
+        }
+
+        fn option(&mut self, name: &str, value: &str) -> Result<(), AssuanError> {
+            self.options.push((name.to_owned(), value.to_owned()));
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            self.options.clear();
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn session() {
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+        use AssuanClient;
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            AssuanServer::new(server.try_clone().unwrap(), server).serve(&mut Echo::default())
+        });
+
+        let mut client = AssuanClient::from_unix_stream(client).unwrap();
+        let res = client.exec("echo", &[b"100% binary\n\xff"]).unwrap();
+        assert_eq!(res.data, b"100% binary\n\xff");
+        assert_eq!(res.status, [("PROGRESS".to_owned(), "echo 1 1".to_owned())]);
+
+        let res = client.exec_with_inquiries("ASK", &[], |keyword: &str, args: &str| {
+            // The arguments arrive escaped, as on the wire
+            assert_eq!((keyword, args), ("WHAT", "100%25"));
+            assert_eq!(::unescape_data(args.as_bytes()), b"100%");
+            Some(vec![b'x'; 2000])
+        }).unwrap();
+        assert_eq!(res.data, vec![b'x'; 2000]);
+        let err = client.exec_with_inquiries("ASK", &[], |_: &str, _: &str| None).unwrap_err();
+        assert_eq!(err.error_code(), Some(error::ASS_CANCELED));
+
+        client.option("display", ":0 1").unwrap();
+        client.option_flag("no-grab").unwrap();
+        assert_eq!(client.exec_data("OPTIONS", &[]).unwrap(), b"display=:0 1,no-grab=");
+        client.exec("RESET", &[]).unwrap();
+        assert_eq!(client.exec_data("OPTIONS", &[]).unwrap(), b"");
+
+        let err = client.exec("NOSUCH", &[]).unwrap_err();
+        assert_eq!(err.error_code(), Some(error::ASS_UNKNOWN_CMD));
+        assert_eq!(err.error_source(), Some(error::SOURCE_ASSUAN));
+        client.exec("NOP", &[]).unwrap();
+
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn long_lines() {
+        let long = vec![b'A'; 5000];
+        let mut input = [&long[..], b"\nNOP\n", b"ASK\nD ", &long[..], b"\n"].concat();
+        let mut replies = Vec::new();
+        let res = AssuanServer::new(&input[..], &mut replies).serve(&mut Echo::default());
+        assert_eq!(IoError::from(res.unwrap_err()).kind(), ErrorKind::InvalidData);
+        let replies = String::from_utf8(replies).unwrap();
+        let lines: Vec<&str> = replies.lines().collect();
+        assert!(lines[1].starts_with("ERR ") && lines[1].ends_with("Line too long"));
+        assert_eq!(&lines[2..], ["OK", "INQUIRE WHAT 100%25"]);
+
+        // Without any newline
+        input.truncate(long.len());
+        let mut replies = Vec::new();
+        AssuanServer::new(&input[..], &mut replies).serve(&mut Echo::default()).unwrap();
+        assert!(String::from_utf8(replies).unwrap().ends_with("Line too long\n"));
+    }
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(parse_option(b"display=:0").unwrap(), ("display".to_owned(), ":0".to_owned()));
+        assert_eq!(parse_option(b"--ttyname /dev/pts/1").unwrap(), ("ttyname".to_owned(), "/dev/pts/1".to_owned()));
+        assert_eq!(parse_option(b"grab").unwrap(), ("grab".to_owned(), "".to_owned()));
+        assert!(parse_option(b"").is_err());
+        assert_eq!(parse_option(b"display=\xff").unwrap_err().code(), Some(assuan_code(error::ASS_INV_VALUE)));
+    }
+
+    #[test]
+    fn getinfo() {
+        let input = b"GETINFO pid\nGETINFO version\nBYE\n";
+        let mut replies = Vec::new();
+        AssuanServer::new(&input[..], &mut replies).version("1.2.3").serve(&mut Echo::default()).unwrap();
+        let replies = String::from_utf8(replies).unwrap();
+        let lines: Vec<&str> = replies.lines().collect();
+        assert_eq!(lines[1], format!("D {}", process::id()));
+        assert_eq!(&lines[2..], ["OK", "D 1.2.3", "OK", "OK closing connection"]);
+
+        // Without a version, the handler answers
+        let mut replies = Vec::new();
+        AssuanServer::new(&input[..], &mut replies).serve(&mut Echo::default()).unwrap();
+        assert!(String::from_utf8(replies).unwrap().contains("Unknown command"));
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+#[cfg(unix)]
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::process::{Child, ChildStdin, ChildStdout};
+use std::time::Duration;
+
+use client::ReadTimeout;
+#[cfg(unix)]
+use fdpass;
+
+/// Carries the lines between an `AssuanClient` and the server, e.g. a
+/// socket, the pipes of a child process or a scripted exchange in tests
+pub trait AssuanTransport {
+    /// Append the next line, with its newline, to `line`. Returns 0 once
+    /// the server closed the connection.
+    fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<usize>;
+
+    /// Write one or more whole lines, each ending with a newline.
+    fn write_lines(&mut self, lines: &[u8]) -> io::Result<()>;
+
+    /// Send the lines written so far.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Pass a file descriptor to the server, see `AssuanClient::send_fd()`.
+    /// Not supported by default.
+    #[cfg(unix)]
+    fn send_fd(&mut self, _fd: RawFd) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "This transport can not pass file descriptors"))
+    }
+
+    /// End the connection, after BYE. Does nothing by default.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader and a writer, e.g. the stdout and stdin of a child
+pub struct StreamTransport<R, W> where R: Read, W: Write {
+    r: BufReader<R>,
+    w: W,
+}
+
+impl<R, W> StreamTransport<R, W> where R: Read, W: Write {
+    pub fn new(r: R, w: W) -> Self {
+        StreamTransport { r: BufReader::new(r), w }
+    }
+
+    /// The reader, e.g. to set a read timeout on a socket.
+    pub fn get_ref(&self) -> &R {
+        self.r.get_ref()
+    }
+}
+
+impl<R, W> AssuanTransport for StreamTransport<R, W> where R: Read, W: Write {
+    fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<usize> {
+        self.r.read_until(b'\n', line)
+    }
+
+    fn write_lines(&mut self, lines: &[u8]) -> io::Result<()> {
+        self.w.write_all(lines)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+impl<R, W> ReadTimeout for StreamTransport<R, W> where R: Read + ReadTimeout, W: Write {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.get_ref().read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+}
+
+/// The stdout and stdin of a child process owned by the transport, e.g. a
+/// pinentry. Closing it waits for the child to exit.
+pub struct ChildTransport {
+    r: BufReader<ChildStdout>,
+    // Taken on close, so the child sees EOF
+    w: Option<ChildStdin>,
+    child: Child,
+}
+
+impl ChildTransport {
+    /// Take the piped stdin and stdout of `child`.
+    pub fn new(mut child: Child) -> io::Result<Self> {
+        match (child.stdin.take(), child.stdout.take()) {
+            (Some(w), Some(r)) => Ok(ChildTransport { r: BufReader::new(r), w: Some(w), child }),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "The child stdin and stdout must be piped"))
+            }
+        }
+    }
+
+    pub fn child(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl AssuanTransport for ChildTransport {
+    /// If the child closes its stdout, it is waited for and the error says
+    /// how it exited.
+    fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<usize> {
+        match self.r.read_until(b'\n', line)? {
+            0 => {
+                let status = self.child.wait()?;
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("The server exited with {}", status)))
+            }
+            len => Ok(len),
+        }
+    }
+
+    fn write_lines(&mut self, lines: &[u8]) -> io::Result<()> {
+        match self.w {
+            Some(ref mut w) => w.write_all(lines),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "The transport is closed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.w {
+            Some(ref mut w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.w.take();
+        self.child.wait().map(|_| ())
+    }
+}
+
+/// A connected Unix socket, which can also pass file descriptors
+#[cfg(unix)]
+pub struct UnixTransport {
+    r: BufReader<UnixStream>,
+    w: UnixStream,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        Ok(UnixTransport { r: BufReader::new(stream.try_clone()?), w: stream })
+    }
+
+    /// The socket, e.g. to set a read timeout.
+    pub fn get_ref(&self) -> &UnixStream {
+        &self.w
+    }
+}
+
+#[cfg(unix)]
+impl AssuanTransport for UnixTransport {
+    fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<usize> {
+        self.r.read_until(b'\n', line)
+    }
+
+    fn write_lines(&mut self, lines: &[u8]) -> io::Result<()> {
+        self.w.write_all(lines)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+
+    fn send_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        fdpass::send_fd(self.w.as_raw_fd(), fd)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.w.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for UnixTransport {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.w.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.w.set_read_timeout(timeout)
+    }
+}
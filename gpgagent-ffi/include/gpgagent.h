@@ -0,0 +1,56 @@
+/*
+ * C bindings for the gpgagent crate
+ *
+ * Functions returning int return 0 on success and -1 on failure, the
+ * error message is then available through gpgagent_last_error().
+ */
+#ifndef GPGAGENT_H
+#define GPGAGENT_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Agent gpgagent_t;
+
+/* Connect to the agent socket at path, or search the standard paths
+ * if path is NULL. Returns NULL on failure. */
+gpgagent_t *gpgagent_connect(const char *path);
+
+/* Close the connection and free the handle */
+void gpgagent_free(gpgagent_t *agent);
+
+/* Message for the last failed call, owned by the handle */
+const char *gpgagent_last_error(const gpgagent_t *agent);
+
+/* Ask for a passphrase. On success *out holds *out_len bytes (not NUL
+ * terminated) that must be released with gpgagent_passphrase_free(). */
+int gpgagent_get_passphrase(gpgagent_t *agent,
+                            const char *cache_id,
+                            const char *error_message,
+                            const char *prompt,
+                            const char *description,
+                            unsigned char **out,
+                            size_t *out_len);
+
+/* Zero and free a passphrase returned by gpgagent_get_passphrase() */
+void gpgagent_passphrase_free(unsigned char *pass, size_t len);
+
+/* Remove a cached passphrase */
+int gpgagent_clear_passphrase(gpgagent_t *agent, const char *cache_id);
+
+/* Store a passphrase for a keygrip, ttl in seconds or negative for the
+ * agent default. Requires --allow-preset-passphrase. */
+int gpgagent_preset_passphrase(gpgagent_t *agent,
+                               const char *keygrip,
+                               int ttl,
+                               const unsigned char *pass,
+                               size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,204 @@
+//! C bindings for the gpgagent crate, see `include/gpgagent.h`.
+//!
+//! Functions returning `int` return 0 on success and -1 on failure, the
+//! error message can then be read with `gpgagent_last_error()`.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+use std::slice;
+
+extern crate libc;
+use libc::{c_char, c_int, size_t};
+
+#[cfg(test)]
+extern crate gpgagent_testutil;
+
+extern crate gpgagent;
+use gpgagent::{GpgAgent, GpgAgentError, UnixStream};
+
+/// Opaque handle given to C callers
+pub struct Agent {
+    agent: GpgAgent<UnixStream, UnixStream>,
+    last_error: CString,
+}
+
+impl Agent {
+    fn result<T>(&mut self, res: Result<T, GpgAgentError>) -> Option<T> {
+        match res {
+            Ok(val) => Some(val),
+            Err(err) => {
+                self.last_error = CString::new(err.to_string().replace('\0', ""))
+                    .unwrap_or_default();
+                None
+            }
+        }
+    }
+}
+
+unsafe fn bytes<'a>(s: *const c_char) -> &'a [u8] {
+    if s.is_null() {
+        b""
+    } else {
+        CStr::from_ptr(s).to_bytes()
+    }
+}
+
+/// Connect to the agent socket at `path`, or search the standard paths if
+/// `path` is NULL. Returns NULL on failure.
+///
+/// # Safety
+///
+/// `path` must be NULL or a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn gpgagent_connect(path: *const c_char) -> *mut Agent {
+    let agent = if path.is_null() {
+        GpgAgent::from_standard_paths()
+    } else {
+        GpgAgent::from_path(OsStr::from_bytes(bytes(path)))
+    };
+
+    match agent {
+        Ok(agent) => Box::into_raw(Box::new(Agent { agent, last_error: CString::default() })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Close the connection and free the handle.
+///
+/// # Safety
+///
+/// `agent` must be NULL or a handle returned by `gpgagent_connect()` that
+/// was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn gpgagent_free(agent: *mut Agent) {
+    if !agent.is_null() {
+        drop(Box::from_raw(agent));
+    }
+}
+
+/// Message for the last failed call on this handle. The string is owned by
+/// the handle and valid until the next call.
+///
+/// # Safety
+///
+/// `agent` must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn gpgagent_last_error(agent: *const Agent) -> *const c_char {
+    (*agent).last_error.as_ptr()
+}
+
+/// Ask for a passphrase, see `GpgAgent::get_passphrase()`. On success `*out`
+/// and `*out_len` hold the passphrase, which must be released with
+/// `gpgagent_passphrase_free()`. It is not NUL terminated.
+///
+/// # Safety
+///
+/// `agent` must be a valid handle, the strings NULL or NUL terminated, and
+/// `out`/`out_len` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gpgagent_get_passphrase(agent: *mut Agent,
+                                                 cache_id: *const c_char,
+                                                 error_message: *const c_char,
+                                                 prompt: *const c_char,
+                                                 description: *const c_char,
+                                                 out: *mut *mut u8,
+                                                 out_len: *mut size_t) -> c_int {
+    let agent = &mut *agent;
+    let res = agent.agent.get_passphrase(bytes(cache_id), bytes(error_message),
+                                         bytes(prompt), bytes(description));
+    match agent.result(res) {
+        Some(pass) => {
+            let pass = pass.into_boxed_slice();
+            *out_len = pass.len();
+            *out = Box::into_raw(pass) as *mut u8;
+            0
+        }
+        None => -1,
+    }
+}
+
+/// Zero and free a passphrase returned by `gpgagent_get_passphrase()`.
+///
+/// # Safety
+///
+/// `pass` and `len` must be exactly as returned by
+/// `gpgagent_get_passphrase()`, or `pass` NULL.
+#[no_mangle]
+pub unsafe extern "C" fn gpgagent_passphrase_free(pass: *mut u8, len: size_t) {
+    if pass.is_null() {
+        return;
+    }
+    let pass = slice::from_raw_parts_mut(pass, len);
+    for b in pass.iter_mut() {
+        ptr::write_volatile(b, 0);
+    }
+    drop(Box::from_raw(pass as *mut [u8]));
+}
+
+/// Remove a cached passphrase, see `GpgAgent::clear_passphrase()`.
+///
+/// # Safety
+///
+/// `agent` must be a valid handle and `cache_id` NUL terminated.
+#[no_mangle]
+pub unsafe extern "C" fn gpgagent_clear_passphrase(agent: *mut Agent, cache_id: *const c_char) -> c_int {
+    let agent = &mut *agent;
+    let res = agent.agent.clear_passphrase(bytes(cache_id));
+    agent.result(res).map_or(-1, |_| 0)
+}
+
+/// Store a passphrase for a keygrip, see `GpgAgent::preset_passphrase()`.
+/// `ttl` is in seconds, negative for the agent default.
+///
+/// # Safety
+///
+/// `agent` must be a valid handle, `keygrip` NUL terminated and `pass`
+/// NULL or valid for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gpgagent_preset_passphrase(agent: *mut Agent,
+                                                    keygrip: *const c_char,
+                                                    ttl: c_int,
+                                                    pass: *const u8,
+                                                    len: size_t) -> c_int {
+    let agent = &mut *agent;
+    let ttl = if ttl < 0 { None } else { Some(ttl as u32) };
+    let pass = if pass.is_null() { &[] } else { slice::from_raw_parts(pass, len) };
+    let res = agent.agent.preset_passphrase(bytes(keygrip), ttl, pass);
+    agent.result(res).map_or(-1, |_| 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpgagent_testutil::TestAgent;
+
+    fn connect(test_agent: &TestAgent) -> *mut Agent {
+        let path = CString::new(test_agent.socket_path().as_os_str().as_bytes()).unwrap();
+        unsafe { gpgagent_connect(path.as_ptr()) }
+    }
+
+    #[test]
+    fn connect_and_clear() {
+        let test_agent = TestAgent::new().unwrap();
+        unsafe {
+            let agent = connect(&test_agent);
+            assert!(!agent.is_null());
+            let cache_id = CString::new("gpgagent:ffi:test").unwrap();
+            assert_eq!(gpgagent_clear_passphrase(agent, cache_id.as_ptr()), 0);
+            gpgagent_free(agent);
+        }
+    }
+
+    #[test]
+    fn last_error() {
+        let test_agent = TestAgent::new().unwrap();
+        unsafe {
+            let agent = connect(&test_agent);
+            let keygrip = CString::new("not a keygrip").unwrap();
+            assert_eq!(gpgagent_preset_passphrase(agent, keygrip.as_ptr(), 0, ptr::null(), 0), -1);
+            assert!(!CStr::from_ptr(gpgagent_last_error(agent)).to_bytes().is_empty());
+            gpgagent_free(agent);
+        }
+    }
+}
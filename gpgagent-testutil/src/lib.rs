@@ -0,0 +1,208 @@
+//! A disposable gpg-agent for tests
+//!
+//! `TestAgent` creates a temporary GnuPG home, starts a gpg-agent for it
+//! that answers every pinentry prompt with a fixed passphrase, and
+//! connects to it. Everything is torn down when it is dropped, so tests
+//! do not depend on (or change) the agent of the user running them.
+//!
+//!     extern crate gpgagent_testutil;
+//!     use gpgagent_testutil::TestAgent;
+//!
+//!     let mut agent = TestAgent::builder().passphrase("secret").start().unwrap();
+//!     assert_eq!(agent.get_passphrase("test", "X", "X", "X").unwrap(), b"secret");
+//!
+//! The agent is started with `allow-preset-passphrase`, so
+//! `PRESET_PASSPHRASE` works as well.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::env;
+
+extern crate assuan;
+extern crate gpgagent;
+use gpgagent::{GpgAgent, UnixStream};
+
+/// Counter to give each agent of this process its own home
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A pinentry that answers GETPIN with the contents of the `pin` file next
+/// to it, or fails every prompt if the file is missing. If there is a
+/// `delay` file it waits that many seconds before answering.
+const PINENTRY: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+echo "OK Pleased to meet you"
+while read -r cmd rest; do
+    case "$cmd" in
+        GETPIN|CONFIRM|MESSAGE)
+            [ -f "$dir/delay" ] && sleep "$(cat "$dir/delay")"
+            if [ -f "$dir/pin" ]; then
+                [ "$cmd" = GETPIN ] && cat "$dir/pin"
+                echo OK
+            else
+                echo "ERR 83886179 Operation cancelled <Pinentry>"
+            fi;;
+        BYE) echo OK; exit 0;;
+        *) echo OK;;
+    esac
+done
+"#;
+
+/// Configuration for a `TestAgent`
+#[derive(Clone, Debug)]
+pub struct TestAgentBuilder {
+    passphrase: Option<Vec<u8>>,
+    delay: Option<Duration>,
+}
+
+impl Default for TestAgentBuilder {
+    fn default() -> Self {
+        TestAgentBuilder { passphrase: Some(b"passphrase".to_vec()), delay: None }
+    }
+}
+
+impl TestAgentBuilder {
+    /// The passphrase the pinentry answers with, `passphrase` by default.
+    pub fn passphrase<P: AsRef<[u8]>>(mut self, passphrase: P) -> Self {
+        self.passphrase = Some(passphrase.as_ref().to_vec());
+        self
+    }
+
+    /// Make the pinentry cancel every prompt, as if the user did.
+    pub fn cancel(mut self) -> Self {
+        self.passphrase = None;
+        self
+    }
+
+    /// Make the pinentry wait before answering, like a user thinking.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Create the home directory, start the agent and connect to it.
+    pub fn start(self) -> io::Result<TestAgent> {
+        let home = env::temp_dir().join(format!("gpgagent-test-{}-{}", process::id(),
+                                                NEXT_ID.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&home)?;
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o700))?;
+
+        // Remove the home again if the agent fails to start
+        let mut guard = Home(Some(home.clone()));
+
+        let pinentry = home.join("pinentry");
+        File::create(&pinentry)?.write_all(PINENTRY.as_bytes())?;
+        fs::set_permissions(&pinentry, fs::Permissions::from_mode(0o700))?;
+        if let Some(ref passphrase) = self.passphrase {
+            File::create(home.join("pin"))?.write_all(&assuan::escape_data_line(passphrase))?;
+        }
+        if let Some(delay) = self.delay {
+            writeln!(File::create(home.join("delay"))?, "{}.{:03}", delay.as_secs(), delay.subsec_millis())?;
+        }
+
+        let mut conf = File::create(home.join("gpg-agent.conf"))?;
+        writeln!(conf, "pinentry-program {}", pinentry.display())?;
+        writeln!(conf, "allow-preset-passphrase")?;
+        drop(conf);
+
+        let agent = GpgAgent::from_home_dir(&home)?;
+        guard.0.take();
+        Ok(TestAgent { home, agent })
+    }
+}
+
+/// Removes a home directory on drop
+struct Home(Option<PathBuf>);
+
+impl Drop for Home {
+    fn drop(&mut self) {
+        if let Some(ref home) = self.0 {
+            kill_agent(home);
+        }
+    }
+}
+
+fn kill_agent(home: &Path) {
+    let _ = Command::new("gpgconf").arg("--homedir").arg(home)
+        .args(["--kill", "gpg-agent"])
+        .status();
+    let _ = fs::remove_dir_all(home);
+}
+
+/// A gpg-agent running for a temporary GnuPG home, and a connection to
+/// it. Derefs to the `GpgAgent`.
+pub struct TestAgent {
+    home: PathBuf,
+    agent: GpgAgent<UnixStream, UnixStream>,
+}
+
+impl TestAgent {
+    /// Start an agent with the default configuration.
+    pub fn new() -> io::Result<Self> {
+        Self::builder().start()
+    }
+
+    pub fn builder() -> TestAgentBuilder {
+        TestAgentBuilder::default()
+    }
+
+    /// The temporary GnuPG home.
+    pub fn home(&self) -> &Path {
+        &self.home
+    }
+
+    /// The agent socket, e.g. to open more connections.
+    pub fn socket_path(&self) -> &Path {
+        self.agent.socket_path().expect("connected through a socket")
+    }
+}
+
+impl Deref for TestAgent {
+    type Target = GpgAgent<UnixStream, UnixStream>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.agent
+    }
+}
+
+impl DerefMut for TestAgent {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.agent
+    }
+}
+
+impl Drop for TestAgent {
+    fn drop(&mut self) {
+        kill_agent(&self.home);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinentry_answers() {
+        let mut agent = TestAgent::builder().passphrase("100% sure\n").start().unwrap();
+        assert_eq!(agent.get_passphrase("testutil", "X", "X", "X").unwrap(), b"100% sure\n");
+        assert!(agent.get_confirmation("Continue?").is_ok());
+
+        let mut agent = TestAgent::builder().cancel().start().unwrap();
+        assert!(agent.get_passphrase("testutil", "X", "X", "X").is_err());
+    }
+
+    #[test]
+    fn teardown() {
+        let agent = TestAgent::new().unwrap();
+        let (home, socket) = (agent.home().to_owned(), agent.socket_path().to_owned());
+        assert!(UnixStream::connect(&socket).is_ok());
+        drop(agent);
+        assert!(!home.exists());
+        assert!(UnixStream::connect(&socket).is_err());
+    }
+}
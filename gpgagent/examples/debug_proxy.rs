@@ -0,0 +1,14 @@
+extern crate gpgagent;
+
+use std::env;
+
+/// Usage: debug_proxy <listen socket> <agent socket>
+fn main() {
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("Usage: debug_proxy <listen socket> <agent socket>");
+        return;
+    }
+    gpgagent::serve_debug_proxy(&args[0], &args[1])
+        .unwrap();
+}
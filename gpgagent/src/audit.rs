@@ -0,0 +1,127 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use assuan::{escape_arg, AssuanError, Outcome};
+
+use keydesc::iso_time;
+use GpgAgentError;
+
+/// An operation recorded in the audit log. It never holds passphrases or
+/// other secrets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuditEvent<'a> {
+    pub time: SystemTime,
+    /// The operation, usually the agent command e.g. "PRESET_PASSPHRASE"
+    /// or "SCD PKSIGN"
+    pub operation: &'a str,
+    /// The keygrip, cache id or card key the operation was about
+    pub subject: Option<&'a str>,
+    pub outcome: Outcome,
+}
+
+impl<'a> AuditEvent<'a> {
+    pub(crate) fn outcome_of<T>(res: &Result<T, GpgAgentError>) -> Outcome {
+        match *res {
+            Ok(_) => Outcome::Ok,
+            Err(GpgAgentError::Protocol(AssuanError::Server { code, .. })) => Outcome::Server(code),
+            Err(_) => Outcome::Failed,
+        }
+    }
+}
+
+/// Receives the operations done through a `GpgAgent`, see
+/// `GpgAgent::set_audit_log()`
+pub trait AuditSink: Send {
+    fn record(&mut self, event: &AuditEvent);
+}
+
+impl<F> AuditSink for F where F: FnMut(&AuditEvent) + Send {
+    fn record(&mut self, event: &AuditEvent) {
+        self(event)
+    }
+}
+
+/// Writes one line per operation, e.g. to a file:
+///
+/// ```text
+/// 20261016T093000 PRESET_PASSPHRASE 0123456789ABCDEF0123456789ABCDEF01234567 OK
+/// 20261016T093012 SCD PKSIGN OPENPGP.1 ERR 100663404
+/// ```
+///
+/// The subject is percent escaped as a command argument, so a cache id
+/// with spaces or newlines can not split or forge entries. Write errors
+/// are ignored, the operations are not held back by the log.
+pub struct AuditWriter<W>(pub W);
+
+impl<W: Write + Send> AuditSink for AuditWriter<W> {
+    fn record(&mut self, event: &AuditEvent) {
+        let secs = event.time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let outcome = match event.outcome {
+            Outcome::Ok => "OK".to_owned(),
+            Outcome::Server(code) => format!("ERR {}", code),
+            Outcome::Failed => "FAILED".to_owned(),
+        };
+        let _ = match event.subject {
+            Some(subject) => writeln!(self.0, "{} {} {} {}", iso_time(secs), event.operation,
+                                      escape_arg(subject.as_bytes()), outcome),
+            None => writeln!(self.0, "{} {} {}", iso_time(secs), event.operation, outcome),
+        };
+        let _ = self.0.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use gpgagent_testutil::TestAgent;
+    use GpgAgent;
+
+    /// A writer whose output can be read while the sink holds it
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_without_secrets() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        let log = Shared::default();
+        agent.set_audit_log(AuditWriter(log.clone()));
+
+        let keygrip = "0123456789ABCDEF0123456789ABCDEF01234567";
+        agent.preset_passphrase(keygrip, None, b"hunter2").unwrap();
+        agent.get_passphrase("audit:test", "X", "X", "X").unwrap();
+        assert!(agent.delete_key(keygrip).is_err());
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Vec<&str>> = log.lines().map(|line| line.splitn(2, ' ').collect()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0][1], format!("PRESET_PASSPHRASE {} OK", keygrip));
+        assert_eq!(lines[1][1], "GET_PASSPHRASE audit:test OK");
+        assert!(lines[2][1].starts_with(&format!("DELETE_KEY {} ERR ", keygrip)));
+        assert!(!log.contains("hunter2") && !log.contains("passphrase"));
+    }
+
+    #[test]
+    fn escaped_subject() {
+        let mut log = AuditWriter(Vec::new());
+        log.record(&AuditEvent {
+            time: UNIX_EPOCH,
+            operation: "CLEAR_PASSPHRASE --mode=normal",
+            subject: Some("id OK\n19700101T000000 PRESET_PASSPHRASE forged"),
+            outcome: Outcome::Server(99),
+        });
+        assert_eq!(String::from_utf8(log.0).unwrap(),
+                   "19700101T000000 CLEAR_PASSPHRASE --mode=normal id%20OK%0A19700101T000000%20PRESET_PASSPHRASE%20forged ERR 99\n");
+    }
+}
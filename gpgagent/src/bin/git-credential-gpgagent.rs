@@ -0,0 +1,104 @@
+//! A git credential helper that keeps passwords in the gpg-agent cache
+//!
+//! https://git-scm.com/docs/gitcredentials
+//!
+//! Configure it with
+//!
+//!     git config --global credential.helper gpgagent
+//!
+//! On `get` the agent prompts for the password through pinentry and keeps
+//! it cached, `erase` clears the cache entry when git reports the password
+//! was rejected. Passwords are only cached by the agent, so `store` does
+//! nothing. The username must be known beforehand, e.g. from the URL or
+//! the `credential.username` setting, otherwise git prompts for both.
+
+extern crate gpgagent;
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::process;
+
+use gpgagent::{GpgAgent, GpgAgentError};
+
+/// Prefix for all cache ids used by this helper
+const CACHE_PREFIX: &str = "git-credential";
+
+/// Parse the key=value attributes git writes to the helper stdin
+fn read_attributes<R: BufRead>(input: R) -> io::Result<HashMap<String, String>> {
+    let mut attrs = HashMap::new();
+    for line in input.lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, val)) = line.split_once('=') {
+            attrs.insert(key.to_owned(), val.to_owned());
+        }
+    }
+    Ok(attrs)
+}
+
+/// The URL the credential is for, without the password
+fn url(attrs: &HashMap<String, String>, username: &str) -> String {
+    let mut url = format!("{}://{}@{}",
+                          attrs.get("protocol").map_or("https", |p| p.as_str()),
+                          username,
+                          attrs.get("host").map_or("", |h| h.as_str()));
+    if let Some(path) = attrs.get("path") {
+        url.push('/');
+        url.push_str(path);
+    }
+    url
+}
+
+fn run(action: &str, attrs: &HashMap<String, String>) -> Result<(), GpgAgentError> {
+    let username = match attrs.get("username") {
+        Some(username) => username,
+        None => return Ok(()),
+    };
+    let url = url(attrs, username);
+    let cache_id = format!("{}:{}", CACHE_PREFIX, url);
+
+    match action {
+        "get" => {
+            let mut agent = GpgAgent::from_standard_paths()?;
+            agent.setopt_ttyname()?;
+            let password = agent.get_passphrase(&cache_id, "X", "Password",
+                                                format!("Password for {}", url))?;
+            println!("username={}", username);
+            println!("password={}", String::from_utf8_lossy(&password));
+            Ok(())
+        }
+        "erase" => {
+            let mut agent = GpgAgent::from_standard_paths()?;
+            agent.clear_passphrase(&cache_id)
+        }
+        // The agent already cached the password when it was entered
+        _ => Ok(()),
+    }
+}
+
+fn main() {
+    let action = match env::args().nth(1) {
+        Some(action) => action,
+        None => {
+            eprintln!("Usage: git-credential-gpgagent <get|store|erase>");
+            process::exit(1);
+        }
+    };
+
+    let stdin = io::stdin();
+    let attrs = match read_attributes(stdin.lock()) {
+        Ok(attrs) => attrs,
+        Err(err) => {
+            eprintln!("git-credential-gpgagent: {}", err);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = run(&action, &attrs) {
+        eprintln!("git-credential-gpgagent: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,48 @@
+//! An askpass program that asks the question through the gpg-agent
+//!
+//! Suitable for `SSH_ASKPASS` and `SUDO_ASKPASS`. The prompt is taken from
+//! the first argument and the answer is printed on stdout. If the user
+//! cancels, or the agent fails, nothing is printed and the exit status is 1.
+//!
+//! When `SSH_ASKPASS_PROMPT` is set to `confirm` the user is only asked to
+//! confirm, and the answer is given by the exit status.
+
+extern crate gpgagent;
+
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::process;
+
+use gpgagent::{GpgAgent, GpgAgentError};
+
+fn run(prompt: &[u8], confirm: bool) -> Result<(), GpgAgentError> {
+    let mut agent = GpgAgent::from_standard_paths()?;
+    agent.setopt_ttyname()?;
+
+    if confirm {
+        return agent.get_confirmation(prompt);
+    }
+
+    // Never cache answers, the same prompt may ask different questions
+    let answer = agent.get_passphrase("X", "X", "X", prompt)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(&answer)
+        .and_then(|_| stdout.write_all(b"\n"))
+        .map_err(|err| GpgAgentError::Protocol(err.into()))
+}
+
+fn main() {
+    let prompt = env::args_os().nth(1)
+        .unwrap_or_else(|| OsString::from("Passphrase"));
+    let confirm = env::var("SSH_ASKPASS_PROMPT")
+        .map(|val| val == "confirm")
+        .unwrap_or(false);
+
+    if let Err(err) = run(prompt.as_bytes(), confirm) {
+        eprintln!("gpgagent-askpass: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,96 @@
+//! A replacement for gpg-preset-passphrase
+//!
+//!     gpgagent-preset-passphrase [--preset] [--ttl SECONDS] [--passphrase-fd FD] KEYGRIP
+//!     gpgagent-preset-passphrase --forget KEYGRIP
+//!
+//! The passphrase is read from stdin, or from the given file descriptor,
+//! up to the first newline. The agent must be started with
+//! `--allow-preset-passphrase`, and current agents do not implement
+//! `--ttl`, the default cache ttl is used instead.
+
+extern crate gpgagent;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::io::FromRawFd;
+use std::process;
+
+use gpgagent::{GpgAgent, GpgAgentError};
+
+const USAGE: &str = "Usage: gpgagent-preset-passphrase [--preset|--forget] \
+                     [--ttl SECONDS] [--passphrase-fd FD] KEYGRIP";
+
+struct Args {
+    forget: bool,
+    ttl: Option<u32>,
+    fd: Option<i32>,
+    keygrip: String,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { forget: false, ttl: None, fd: None, keygrip: String::new() };
+    let mut argv = env::args().skip(1);
+
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--preset" => args.forget = false,
+            "--forget" => args.forget = true,
+            "--ttl" => {
+                let ttl = argv.next().ok_or("--ttl requires a value")?;
+                args.ttl = Some(ttl.parse().map_err(|_| format!("Invalid ttl: {}", ttl))?);
+            }
+            "--passphrase-fd" => {
+                let fd = argv.next().ok_or("--passphrase-fd requires a value")?;
+                args.fd = Some(fd.parse().map_err(|_| format!("Invalid fd: {}", fd))?);
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if args.keygrip.is_empty() => args.keygrip = arg,
+            _ => return Err("Only one keygrip can be given".to_owned()),
+        }
+    }
+
+    if args.keygrip.is_empty() {
+        return Err(USAGE.to_owned());
+    }
+    Ok(args)
+}
+
+/// Read the passphrase up to the first newline
+fn read_passphrase<R: Read>(input: R) -> io::Result<Vec<u8>> {
+    let mut passphrase = Vec::new();
+    BufReader::new(input).read_until(b'\n', &mut passphrase)?;
+    if passphrase.last() == Some(&b'\n') {
+        passphrase.pop();
+    }
+    if passphrase.last() == Some(&b'\r') {
+        passphrase.pop();
+    }
+    Ok(passphrase)
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let mut agent = GpgAgent::from_standard_paths()
+        .map_err(|err: GpgAgentError| err.to_string())?;
+
+    if args.forget {
+        return agent.forget_passphrase(&args.keygrip)
+            .map_err(|err| err.to_string());
+    }
+
+    let passphrase = match args.fd {
+        Some(fd) => read_passphrase(unsafe { File::from_raw_fd(fd) }),
+        None => read_passphrase(io::stdin()),
+    }.map_err(|err| err.to_string())?;
+
+    agent.preset_passphrase(&args.keygrip, args.ttl, &passphrase)
+        .map_err(|err| err.to_string())
+}
+
+fn main() {
+    let res = parse_args().and_then(|args| run(&args));
+    if let Err(err) = res {
+        eprintln!("gpgagent-preset-passphrase: {}", err);
+        process::exit(1);
+    }
+}
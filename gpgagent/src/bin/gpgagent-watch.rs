@@ -0,0 +1,89 @@
+//! Watch the gpg-agent for changes and print them as JSON, one event
+//! per line
+//!
+//!     gpgagent-watch [--interval SECONDS]
+//!
+//! The agent event counters are polled, the first line describes the
+//! current state and the following lines describe changes e.g.
+//!
+//! ```text
+//! {"event":"state","card":"D2760001240102010006012345670000"}
+//! {"event":"card_removed"}
+//! {"event":"keys_changed"}
+//! {"event":"card_inserted","card":"D2760001240102010006012345670000"}
+//! ```
+
+extern crate gpgagent;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use gpgagent::{GpgAgent, GpgAgentError};
+
+fn card_json(card: &Option<String>) -> String {
+    match *card {
+        // Serial numbers are hex strings, no escaping needed
+        Some(ref serialno) => format!("\"{}\"", serialno),
+        None => "null".to_owned(),
+    }
+}
+
+fn emit(line: &str) -> Result<(), GpgAgentError> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(stdout, "{}", line)
+        .and_then(|_| stdout.flush())
+        .map_err(|err| GpgAgentError::Protocol(err.into()))
+}
+
+fn watch(interval: Duration) -> Result<(), GpgAgentError> {
+    let mut agent = GpgAgent::from_standard_paths()?;
+    let mut counters = agent.event_counters()?;
+    let mut card = agent.card_serialno().ok();
+    emit(&format!("{{\"event\":\"state\",\"card\":{}}}", card_json(&card)))?;
+
+    loop {
+        thread::sleep(interval);
+        let current = agent.event_counters()?;
+
+        if current.key != counters.key {
+            emit("{\"event\":\"keys_changed\"}")?;
+        }
+        if current.card != counters.card {
+            let current_card = agent.card_serialno().ok();
+            if current_card != card {
+                if card.is_some() {
+                    emit("{\"event\":\"card_removed\"}")?;
+                }
+                if current_card.is_some() {
+                    emit(&format!("{{\"event\":\"card_inserted\",\"card\":{}}}",
+                                  card_json(&current_card)))?;
+                }
+                card = current_card;
+            }
+        }
+        counters = current;
+    }
+}
+
+fn main() {
+    let mut interval = 2;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next().map(|val| val.parse())) {
+            ("--interval", Some(Ok(val))) => interval = val,
+            _ => {
+                eprintln!("Usage: gpgagent-watch [--interval SECONDS]");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Err(err) = watch(Duration::from_secs(interval)) {
+        eprintln!("gpgagent-watch: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,84 @@
+//! Names used when talking to the gpg-agent
+
+pub use assuan::constants::{error, status};
+
+/// Session options set with `GpgAgent::option()`
+pub mod option {
+    pub const TTYNAME: &str = "ttyname";
+    pub const TTYTYPE: &str = "ttytype";
+    pub const DISPLAY: &str = "display";
+    pub const XAUTHORITY: &str = "xauthority";
+    pub const LC_CTYPE: &str = "lc-ctype";
+    pub const LC_MESSAGES: &str = "lc-messages";
+    pub const PUTENV: &str = "putenv";
+    pub const PINENTRY_MODE: &str = "pinentry-mode";
+    pub const PINENTRY_USER_DATA: &str = "pinentry-user-data";
+    pub const ALLOW_PINENTRY_NOTIFY: &str = "allow-pinentry-notify";
+    pub const AGENT_AWARENESS: &str = "agent-awareness";
+    pub const USE_CACHE_FOR_SIGNING: &str = "use-cache-for-signing";
+    pub const CACHE_TTL_OPT_PRESET: &str = "cache-ttl-opt-preset";
+    pub const S2K_COUNT: &str = "s2k-count";
+    pub const PRETEND_REQUEST_ORIGIN: &str = "pretend-request-origin";
+    pub const GRAB: &str = "grab";
+    pub const NO_GRAB: &str = "no-grab";
+}
+
+/// The session options known to the gpg-agent, usable wherever an option
+/// name is expected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AgentOption {
+    TtyName,
+    TtyType,
+    Display,
+    Xauthority,
+    LcCtype,
+    LcMessages,
+    Putenv,
+    PinentryMode,
+    PinentryUserData,
+    AllowPinentryNotify,
+    AgentAwareness,
+    UseCacheForSigning,
+    CacheTtlOptPreset,
+    S2kCount,
+    PretendRequestOrigin,
+    Grab,
+    NoGrab,
+}
+
+impl AgentOption {
+    /// The option name sent to the agent
+    pub fn name(self) -> &'static str {
+        match self {
+            AgentOption::TtyName => option::TTYNAME,
+            AgentOption::TtyType => option::TTYTYPE,
+            AgentOption::Display => option::DISPLAY,
+            AgentOption::Xauthority => option::XAUTHORITY,
+            AgentOption::LcCtype => option::LC_CTYPE,
+            AgentOption::LcMessages => option::LC_MESSAGES,
+            AgentOption::Putenv => option::PUTENV,
+            AgentOption::PinentryMode => option::PINENTRY_MODE,
+            AgentOption::PinentryUserData => option::PINENTRY_USER_DATA,
+            AgentOption::AllowPinentryNotify => option::ALLOW_PINENTRY_NOTIFY,
+            AgentOption::AgentAwareness => option::AGENT_AWARENESS,
+            AgentOption::UseCacheForSigning => option::USE_CACHE_FOR_SIGNING,
+            AgentOption::CacheTtlOptPreset => option::CACHE_TTL_OPT_PRESET,
+            AgentOption::S2kCount => option::S2K_COUNT,
+            AgentOption::PretendRequestOrigin => option::PRETEND_REQUEST_ORIGIN,
+            AgentOption::Grab => option::GRAB,
+            AgentOption::NoGrab => option::NO_GRAB,
+        }
+    }
+
+    /// Whether the option is a flag, set with `option_bool()` instead of
+    /// being given a value
+    pub fn is_flag(self) -> bool {
+        matches!(self, AgentOption::AllowPinentryNotify | AgentOption::Grab | AgentOption::NoGrab)
+    }
+}
+
+impl AsRef<[u8]> for AgentOption {
+    fn as_ref(&self) -> &[u8] {
+        self.name().as_bytes()
+    }
+}
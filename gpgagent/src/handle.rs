@@ -0,0 +1,80 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use assuan::AssuanError;
+
+use {GpgAgent, GpgAgentError, UnixStream};
+
+type Agent = GpgAgent<UnixStream, UnixStream>;
+type Job = Box<dyn FnOnce(&mut Agent) + Send>;
+
+/// A connection owned by a worker thread, shared by cloning the handle
+///
+///     # use gpgagent::{AgentHandle, GpgAgent};
+///     # use std::thread;
+///     let handle = AgentHandle::spawn(GpgAgent::from_standard_paths().unwrap());
+///     let other = handle.clone();
+///     thread::spawn(move || other.call(|agent| agent.version()).unwrap());
+///     println!("{}", handle.call(|agent| agent.version()).unwrap());
+///
+/// Requests run one at a time in the order they arrive, so a prompt
+/// blocks the requests of every other handle until it is answered. The
+/// worker stops, closing the connection, once all handles are dropped.
+#[derive(Clone)]
+pub struct AgentHandle {
+    jobs: Sender<Job>,
+}
+
+fn worker_stopped() -> GpgAgentError {
+    AssuanError::Other("The agent worker thread stopped".to_owned()).into()
+}
+
+impl AgentHandle {
+    /// Move `agent` to a new worker thread.
+    pub fn spawn(mut agent: Agent) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::spawn(move || {
+            for job in queue {
+                job(&mut agent);
+            }
+        });
+        AgentHandle { jobs }
+    }
+
+    /// Run `f` with the connection on the worker thread and wait for its
+    /// result.
+    pub fn call<T, F>(&self, f: F) -> Result<T, GpgAgentError>
+        where T: Send + 'static,
+              F: FnOnce(&mut Agent) -> Result<T, GpgAgentError> + Send + 'static
+    {
+        let (reply, result) = mpsc::channel();
+        self.jobs.send(Box::new(move |agent: &mut Agent| {
+            let _ = reply.send(f(agent));
+        })).map_err(|_| worker_stopped())?;
+        result.recv().map_err(|_| worker_stopped())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpgagent_testutil::TestAgent;
+
+    #[test]
+    fn shared_between_threads() {
+        let test_agent = TestAgent::new().unwrap();
+        let handle = AgentHandle::spawn(GpgAgent::from_path(test_agent.socket_path()).unwrap());
+
+        let workers: Vec<_> = (0..4).map(|_| {
+            let handle = handle.clone();
+            thread::spawn(move || handle.call(|agent| agent.version()).unwrap())
+        }).collect();
+        for worker in workers {
+            assert!(worker.join().unwrap().starts_with('2'));
+        }
+
+        // A panic stops the worker, later calls fail instead of hanging
+        assert!(handle.call(|_| -> Result<(), GpgAgentError> { panic!("in job") }).is_err());
+        assert!(handle.call(|agent| agent.version()).is_err());
+    }
+}
@@ -1,22 +1,134 @@
-use std::ffi;
-use std::ptr::null_mut;
+use std::env;
+use std::ffi::{self, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+#[cfg(not(feature = "legacy-unix-socket"))]
+use std::os::unix::net::SocketAddr;
+
+use {UnixListener, UnixStream};
 
 extern crate libc;
 
 #[cfg(unix)]
-pub fn get_ttyname() -> Option<String> {
+pub fn get_ttyname() -> Option<Vec<u8>> {
     let ptr = unsafe { libc::ttyname(0) };
-    if ptr == null_mut() {
+    if ptr.is_null() {
         return None;
     }
 
     let c_str = unsafe { ffi::CStr::from_ptr(ptr) };
-    c_str.to_str()
-        .ok()
-        .map(|v| v.to_owned())
+    Some(c_str.to_bytes().to_vec())
 }
 
 #[cfg(unix)]
 pub fn getuid() -> libc::uid_t {
     unsafe { libc::getuid() }
 }
+
+/// The locale of `category` (e.g. `LC_MESSAGES`) from the environment,
+/// with the usual precedence of `LC_ALL`, then the category, then `LANG`
+pub fn get_locale(category: &str) -> Option<Vec<u8>> {
+    ["LC_ALL", category, "LANG"].iter()
+        .filter_map(env::var_os)
+        .find(|val| !val.is_empty())
+        .map(|val| val.into_vec())
+}
+
+/// The socket to connect to for `path`. gnupg can put a redirect file in
+/// place of a socket, e.g. when the real socket path is too long:
+///
+/// ```text
+/// %Assuan%
+/// socket=${XDG_RUNTIME_DIR}/gnupg/S.gpg-agent
+/// ```
+///
+/// `${NAME}` is replaced by the environment variable. Any other path is
+/// returned as it is.
+pub fn follow_redirect(path: &Path) -> PathBuf {
+    redirect_target(path).unwrap_or_else(|| path.to_owned())
+}
+
+fn redirect_target(path: &Path) -> Option<PathBuf> {
+    // Redirect files are tiny, do not read anything else
+    let meta = fs::metadata(path).ok()?;
+    if !meta.is_file() || meta.len() > 4096 {
+        return None;
+    }
+    let contents = fs::read(path).ok()?;
+    let rest = contents.strip_prefix(b"%Assuan%\n")?;
+    let target = rest.split(|&b| b == b'\n').find_map(|line| line.strip_prefix(b"socket="))?;
+    Some(PathBuf::from(OsString::from_vec(expand_vars(target))))
+}
+
+/// Replace each `${NAME}` with the variable, unset ones are removed
+fn expand_vars(mut value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    while let Some(start) = value.windows(2).position(|w| w == b"${") {
+        let end = match value[start..].iter().position(|&b| b == b'}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.extend_from_slice(&value[..start]);
+        let name = OsString::from_vec(value[start + 2..end].to_vec());
+        if let Some(var) = env::var_os(name) {
+            out.extend(var.into_vec());
+        }
+        value = &value[end + 1..];
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+/// The abstract socket name of `path`, the bytes after a leading NUL
+#[cfg(not(feature = "legacy-unix-socket"))]
+fn abstract_name(path: &Path) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    match path.as_os_str().as_bytes() {
+        [0, name @ ..] => Some(name),
+        _ => None,
+    }
+}
+
+/// Connect to the socket at `path`. On Linux a path starting with a NUL
+/// byte names a socket in the abstract namespace.
+#[cfg(not(feature = "legacy-unix-socket"))]
+pub fn connect(path: &Path) -> io::Result<UnixStream> {
+    match abstract_name(path) {
+        Some(name) => UnixStream::connect_addr(&abstract_addr(name)?),
+        None => UnixStream::connect(path),
+    }
+}
+
+/// Bind a listener to `path`, see `connect()` for abstract sockets.
+#[cfg(not(feature = "legacy-unix-socket"))]
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match abstract_name(path) {
+        Some(name) => UnixListener::bind_addr(&abstract_addr(name)?),
+        None => UnixListener::bind(path),
+    }
+}
+
+#[cfg(all(not(feature = "legacy-unix-socket"), target_os = "linux"))]
+fn abstract_addr(name: &[u8]) -> io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(all(not(feature = "legacy-unix-socket"), not(target_os = "linux")))]
+fn abstract_addr(_: &[u8]) -> io::Result<SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Abstract sockets are only supported on Linux"))
+}
+
+// The unix_socket crate already treats a leading NUL as an abstract name
+#[cfg(feature = "legacy-unix-socket")]
+pub fn connect(path: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(path)
+}
+
+#[cfg(feature = "legacy-unix-socket")]
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    UnixListener::bind(path)
+}
@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io::{self, Read};
+
+use sha2::{Digest, Sha256};
+
+/// The iteration count of an encoded S2K count byte (RFC 4880 3.7.1.3)
+fn decode_count(c: u8) -> u32 {
+    (16 + (c as u32 & 15)) << ((c >> 4) + 6)
+}
+
+/// The smallest count S2K can encode that is at least `count`
+fn round_count(count: u64) -> u32 {
+    (0..=255).map(decode_count)
+        .find(|&n| n as u64 >= count)
+        .unwrap_or_else(|| decode_count(255))
+}
+
+/// Iterated and salted S2K with SHA-256, as used by OpenPGP cards to
+/// hash PINs. `count` is the number of bytes hashed, it is not rounded.
+pub fn s2k_iterated(pin: &[u8], salt: &[u8], count: u32) -> [u8; 32] {
+    let data = [salt, pin].concat();
+    let mut left = (count as usize).max(data.len());
+    let mut hasher = Sha256::new();
+    while left > 0 {
+        let n = left.min(data.len());
+        hasher.update(&data[..n]);
+        left -= n;
+    }
+    hasher.finalize().into()
+}
+
+/// Build the KDF data object for an OpenPGP card, the same gpg writes on
+/// `kdf-setup`. PINs are hashed with `s2k_iterated()` and `count`, rounded
+/// up to a count S2K can encode, using random salts. The object includes
+/// the hashes of `user_pin` and `admin_pin`, so the card can check them
+/// right after it is written.
+///
+/// With `single_salt` the user and admin PIN share one salt and there is
+/// none for the reset code, some cards only support this form.
+pub fn kdf_data(user_pin: &[u8], admin_pin: &[u8], count: u64, single_salt: bool) -> io::Result<Vec<u8>> {
+    let mut salts = [0; 24];
+    File::open("/dev/urandom")?.read_exact(&mut salts)?;
+    Ok(kdf_data_with_salts(user_pin, admin_pin, round_count(count), single_salt, &salts))
+}
+
+fn kdf_data_with_salts(user_pin: &[u8], admin_pin: &[u8], count: u32, single_salt: bool,
+                       salts: &[u8; 24]) -> Vec<u8> {
+    // KDF algorithm iterated S2K, hash SHA-256, then the iteration count
+    let mut data = vec![0x81, 0x01, 0x03, 0x82, 0x01, 0x08, 0x83, 0x04];
+    data.extend_from_slice(&count.to_be_bytes());
+
+    let (user_salt, reset_salt, admin_salt) = (&salts[..8], &salts[8..16], &salts[16..]);
+    data.extend_from_slice(&[0x84, 0x08]);
+    data.extend_from_slice(user_salt);
+    let admin_salt = if single_salt {
+        user_salt
+    } else {
+        data.extend_from_slice(&[0x85, 0x08]);
+        data.extend_from_slice(reset_salt);
+        data.extend_from_slice(&[0x86, 0x08]);
+        data.extend_from_slice(admin_salt);
+        admin_salt
+    };
+
+    data.extend_from_slice(&[0x87, 0x20]);
+    data.extend_from_slice(&s2k_iterated(user_pin, user_salt, count));
+    data.extend_from_slice(&[0x88, 0x20]);
+    data.extend_from_slice(&s2k_iterated(admin_pin, admin_salt, count));
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::ToHex;
+
+    #[test]
+    fn counts() {
+        assert_eq!(decode_count(0), 1024);
+        assert_eq!(decode_count(255), 65011712);
+        assert_eq!(round_count(0), 1024);
+        assert_eq!(round_count(65536), 65536);
+        assert_eq!(round_count(65537), 69632);
+        assert_eq!(round_count(u64::MAX), 65011712);
+    }
+
+    #[test]
+    fn data_object() {
+        assert_eq!(s2k_iterated(b"123456", b"saltsalt", 65536).to_hex(),
+                   "4dafd0a9ccbca09f15300071b5859af02b3bca1b13445d847b0f67b8b9df2b8d");
+
+        let salts = [7; 24];
+        let data = kdf_data_with_salts(b"123456", b"12345678", 65536, false, &salts);
+        assert_eq!(data.len(), 110);
+        assert_eq!(&data[..12], &[0x81, 0x01, 0x03, 0x82, 0x01, 0x08, 0x83, 0x04, 0, 1, 0, 0]);
+        assert_eq!(&data[42..44], &[0x87, 0x20]);
+        assert_eq!(&data[44..76], &s2k_iterated(b"123456", &[7; 8], 65536)[..]);
+
+        let data = kdf_data_with_salts(b"123456", b"12345678", 65536, true, &salts);
+        assert_eq!(data.len(), 90);
+        assert_eq!(&data[56..58], &[0x88, 0x20]);
+        assert_eq!(&data[58..], &s2k_iterated(b"12345678", &[7; 8], 65536)[..]);
+        assert_eq!(kdf_data(b"123456", b"12345678", 65536, false).unwrap().len(), 110);
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+/// The details of a key shown when the agent asks for its passphrase,
+/// formatted like gpg does e.g.
+///
+///     # use gpgagent::KeyDescription;
+///     let desc = KeyDescription {
+///         user_id: "Alice <alice@example.org>".to_owned(),
+///         bits: 3072,
+///         algorithm: "RSA".to_owned(),
+///         key_id: "0123456789ABCDEF".to_owned(),
+///         created: 1577836800,
+///         main_key_id: None,
+///     };
+///     assert_eq!(desc.to_string(),
+///                "Please enter the passphrase to unlock the OpenPGP secret key:\n\
+///                 \"Alice <alice@example.org>\"\n\
+///                 3072-bit RSA key, ID 0123456789ABCDEF,\n\
+///                 created 2020-01-01.\n");
+///
+/// Pass it to `GpgAgent::set_key_description()` before the command that
+/// needs the key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyDescription {
+    pub user_id: String,
+    pub bits: u32,
+    /// The algorithm name as gpg shows it, e.g. "RSA" or "ECDSA"
+    pub algorithm: String,
+    pub key_id: String,
+    /// Creation time, in seconds since the epoch
+    pub created: u64,
+    /// The key id of the primary key, when the key is a subkey
+    pub main_key_id: Option<String>,
+}
+
+/// Civil date (year, month, day) in UTC of a time in seconds since the
+/// epoch
+fn civil_date(secs: u64) -> (i64, i64, i64) {
+    // Civil date from days since the epoch, by Howard Hinnant
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format a time as YYYY-MM-DD in UTC, like gpg
+fn format_date(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Format a time as the ISO timestamp used in commands, YYYYMMDDTHHMMSS
+/// in UTC
+pub(crate) fn iso_time(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs);
+    let time = secs % 86400;
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}", year, month, day,
+            time / 3600, time / 60 % 60, time % 60)
+}
+
+impl fmt::Display for KeyDescription {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Please enter the passphrase to unlock the OpenPGP secret key:\n\
+                     \"{}\"\n\
+                     {}-bit {} key, ID {},\n\
+                     created {}",
+               self.user_id, self.bits, self.algorithm, self.key_id, format_date(self.created))?;
+        if let Some(ref main_key_id) = self.main_key_id {
+            write!(fmt, " (main key ID {})", main_key_id)?;
+        }
+        writeln!(fmt, ".")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951782400), "2000-02-29");
+        assert_eq!(format_date(4102444799), "2099-12-31");
+        assert_eq!(iso_time(0), "19700101T000000");
+        assert_eq!(iso_time(4102444799), "20991231T235959");
+    }
+
+    #[test]
+    fn subkey() {
+        let desc = KeyDescription {
+            user_id: "Bob".to_owned(),
+            bits: 255,
+            algorithm: "ECDH".to_owned(),
+            key_id: "FEDCBA9876543210".to_owned(),
+            created: 0,
+            main_key_id: Some("0123456789ABCDEF".to_owned()),
+        };
+        assert!(desc.to_string().ends_with("created 1970-01-01 (main key ID 0123456789ABCDEF).\n"));
+    }
+}
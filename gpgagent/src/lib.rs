@@ -1,28 +1,103 @@
 //! A crate to interact with the gpg-agent.
 //!
 //! https://www.gnupg.org/documentation/manuals/gnupg/Agent-Protocol.html#Agent-Protocol
+//!
+//! Only Unix is supported. On Windows gnupg uses emulated sockets, which
+//! `assuan::AssuanClient::connect_emulated()` can open, but `GpgAgent` is
+//! built on `UnixStream` and is not available there.
 
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::collections::HashMap;
 use std::fmt;
 use std::env;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+/// The socket type used to connect to the agent, from the standard library
+/// unless the `legacy-unix-socket` feature is enabled.
+#[cfg(not(feature = "legacy-unix-socket"))]
+pub use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(feature = "legacy-unix-socket")]
 extern crate unix_socket;
-use unix_socket::UnixStream;
+#[cfg(feature = "legacy-unix-socket")]
+pub use unix_socket::{UnixListener, UnixStream};
 
 extern crate assuan;
-use assuan::{AssuanClient, AssuanError};
+use assuan::{AssuanClient, AssuanError, StreamTransport};
+pub use assuan::{InquirePolicy, Metrics, Outcome};
 
 extern crate rustc_serialize;
-use rustc_serialize::hex::FromHex;
+use rustc_serialize::hex::{FromHex, ToHex};
+
+extern crate sha2;
+
+#[cfg(test)]
+extern crate gpgagent_testutil;
+
+#[cfg(feature = "secrecy")]
+extern crate secrecy;
+#[cfg(feature = "secrecy")]
+use secrecy::SecretVec;
+#[cfg(feature = "secrecy")]
+use secrecy::zeroize::Zeroize;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+pub mod constants;
+use constants::{error, option, status};
+
+mod audit;
+pub use audit::{AuditEvent, AuditSink, AuditWriter};
+
+mod handle;
+pub use handle::AgentHandle;
 
 mod helpers;
-use helpers::{getuid, get_ttyname};
+use helpers::{getuid, get_locale, get_ttyname};
+
+mod kdf;
+pub use kdf::{kdf_data, s2k_iterated};
+
+mod keydesc;
+pub use keydesc::KeyDescription;
+
+mod manager;
+pub use manager::AgentManager;
+
+mod prompt;
+pub use prompt::PassphrasePrompt;
+
+mod vault;
+pub use vault::SecretVault;
+
+mod proxy;
+pub use proxy::{serve_proxy, serve_debug_proxy, ForwardSocket};
 
 pub enum GpgAgentError {
     SocketNotFound,
     Protocol(AssuanError),
     InvalidPassword,
+    /// A text for the pinentry that the agent would reject
+    InvalidText(String),
+    /// The confirmation hook refused a destructive command
+    Denied,
+    /// There is no socket at any of these paths
+    NotFound(Vec<PathBuf>),
+    /// The socket exists, but no agent is listening on it
+    NotRunning(PathBuf),
+    /// The socket exists, but this user can not connect to it
+    PermissionDenied(PathBuf),
+    /// The path is not a socket, or what listens on it is not an agent
+    StaleSocket(PathBuf),
 }
 
 impl fmt::Display for GpgAgentError {
@@ -31,16 +106,36 @@ impl fmt::Display for GpgAgentError {
             GpgAgentError::SocketNotFound => write!(fmt, "Unable to find the gpg-agent socket"),
             GpgAgentError::Protocol(ref err) => err.fmt(fmt),
             GpgAgentError::InvalidPassword => write!(fmt, "Agent returned an invalid password"),
+            GpgAgentError::InvalidText(ref desc) => write!(fmt, "{}", desc),
+            GpgAgentError::Denied => write!(fmt, "Command denied by the confirmation hook"),
+            GpgAgentError::NotFound(ref paths) => {
+                let paths: Vec<_> = paths.iter().map(|path| path.display().to_string()).collect();
+                write!(fmt, "No gpg-agent socket found, tried {}", paths.join(", "))
+            }
+            GpgAgentError::NotRunning(ref path) =>
+                write!(fmt, "No gpg-agent is listening on {}, start it with `gpgconf --launch gpg-agent`",
+                       path.display()),
+            GpgAgentError::PermissionDenied(ref path) =>
+                write!(fmt, "Permission denied connecting to {}, if the socket is mounted from another \
+                             user namespace map the uid of the agent into it (e.g. podman --userns=keep-id) \
+                             or run as the user owning the socket", path.display()),
+            GpgAgentError::StaleSocket(ref path) =>
+                write!(fmt, "{} is not a gpg-agent socket, remove it and restart the agent", path.display()),
         }
     }
 }
 
 impl fmt::Debug for GpgAgentError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, fmt)
+    }
+}
+
+impl std::error::Error for GpgAgentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
-            GpgAgentError::SocketNotFound => write!(fmt, "Unable to find the gpg-agent socket"),
-            GpgAgentError::Protocol(ref err) => err.fmt(fmt),
-            GpgAgentError::InvalidPassword => write!(fmt, "Agent returned an invalid password"),
+            GpgAgentError::Protocol(ref err) => Some(err),
+            _ => None,
         }
     }
 }
@@ -51,48 +146,655 @@ impl From<AssuanError> for GpgAgentError {
     }
 }
 
+impl From<GpgAgentError> for io::Error {
+    fn from(err: GpgAgentError) -> Self {
+        match err {
+            GpgAgentError::SocketNotFound | GpgAgentError::NotFound(_) =>
+                io::Error::new(io::ErrorKind::NotFound, err.to_string()),
+            GpgAgentError::NotRunning(_) => io::Error::new(io::ErrorKind::ConnectionRefused, err.to_string()),
+            GpgAgentError::StaleSocket(_) => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+            GpgAgentError::Protocol(err) => err.into(),
+            GpgAgentError::InvalidPassword => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+            GpgAgentError::InvalidText(desc) => io::Error::new(io::ErrorKind::InvalidInput, desc),
+            GpgAgentError::Denied | GpgAgentError::PermissionDenied(_) =>
+                io::Error::new(io::ErrorKind::PermissionDenied, err.to_string()),
+        }
+    }
+}
+
+/// Counters the agent increments on changes, as returned by
+/// `GETEVENTCOUNTER`. Polling them is a cheap way to notice changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EventCounters {
+    /// Incremented on any change
+    pub any: u64,
+    /// Incremented when keys are added or removed
+    pub key: u64,
+    /// Incremented when the card status changes
+    pub card: u64,
+}
+
+/// A key generated on a smartcard, see `GpgAgent::card_genkey()`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CardKey {
+    /// The OpenPGP fingerprint, in hex
+    pub fingerprint: String,
+    /// Creation time, in seconds since the epoch
+    pub created: Option<u64>,
+    /// The public key parameters by name, e.g. `n` and `e` for RSA or `q`
+    /// for ECC keys
+    pub public_key: Vec<(String, Vec<u8>)>,
+}
+
+/// Whether a smartcard can be used, see `GpgAgent::card_state()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CardState {
+    /// scdaemon found no card reader
+    NoReader,
+    /// There is a reader, but no card in it
+    NoCard,
+    /// A card is present
+    Present,
+}
+
+/// When a card asks for a touch of its button before using a key, as set
+/// by the YubiKey UIF attributes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TouchPolicy {
+    Off,
+    On,
+    /// Same as `On`, but can not be turned off again without replacing
+    /// the key
+    Fixed,
+}
+
+impl TouchPolicy {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(TouchPolicy::Off),
+            1 => Some(TouchPolicy::On),
+            2 => Some(TouchPolicy::Fixed),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            TouchPolicy::Off => 0,
+            TouchPolicy::On => 1,
+            TouchPolicy::Fixed => 2,
+        }
+    }
+}
+
+/// The kind of connection to the agent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+    UnixSocket,
+    /// The stdin and stdout of a command, e.g. an SSH tunnel
+    Command,
+}
+
+/// The state of the agent and the connection to it, see
+/// `GpgAgent::status()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentStatus {
+    pub socket_path: Option<PathBuf>,
+    pub transport: TransportKind,
+    pub connected_since: SystemTime,
+    /// The agent version, e.g. "2.4.4"
+    pub version: String,
+    pub pid: u32,
+    pub scdaemon_running: bool,
+    /// The number of secret keys the agent has
+    pub keys: usize,
+    /// How many of them have their passphrase cached
+    pub cached_keys: usize,
+    pub event_counters: EventCounters,
+}
+
 pub struct GpgAgent<R, W> where R: Read, W: Write {
-    client: AssuanClient<R, W>,
+    client: AssuanClient<StreamTransport<R, W>>,
+    socket_path: Option<PathBuf>,
+    connected_since: SystemTime,
+    transport: TransportKind,
+    options: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The socket, to change its timeouts
+    stream: Option<UnixStream>,
+    read_timeout: Option<Duration>,
+    interactive_timeout: Option<Duration>,
+    /// Shared with duplicates, like the audit log and the metrics
+    confirm: Option<Arc<Mutex<ConfirmHook>>>,
+    audit: Option<Arc<Mutex<dyn AuditSink>>>,
+    metrics: Option<Arc<Mutex<dyn Metrics>>>,
+    /// The command connecting to the agent, dropped after the client
+    _process: Option<ChildGuard>,
+    /// Answers to `command_has_option()`, by command and option
+    has_option: HashMap<(String, String), bool>,
+}
+
+/// Whether the file at `path` belongs to the current user
+fn owned_by_user(path: &Path) -> bool {
+    std::fs::metadata(path).map(|meta| meta.uid() == getuid()).unwrap_or(false)
+}
+
+/// The error for a failure to connect to the socket at `path`, telling
+/// the user what to fix
+fn connect_error(path: &Path, err: AssuanError) -> GpgAgentError {
+    let kind = match err {
+        AssuanError::IoError(ref err) => err.kind(),
+        err => return err.into(),
+    };
+    let not_socket = std::fs::metadata(path).map(|meta| !meta.file_type().is_socket()).unwrap_or(false);
+    match kind {
+        io::ErrorKind::NotFound => GpgAgentError::NotFound(vec![path.to_owned()]),
+        io::ErrorKind::PermissionDenied => GpgAgentError::PermissionDenied(path.to_owned()),
+        io::ErrorKind::ConnectionRefused if !not_socket => GpgAgentError::NotRunning(path.to_owned()),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset =>
+            GpgAgentError::StaleSocket(path.to_owned()),
+        _ => err.into(),
+    }
+}
+
+/// Waits for a child process once the connection through it is closed
+struct ChildGuard(Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.0.wait();
+    }
+}
+
+type ConfirmHook = dyn FnMut(Destructive) -> bool + Send;
+
+/// Bytes of data sent per SCD SETDATA line, so the hex fits a line
+const SETDATA_CHUNK: usize = 480;
+
+/// A command that can not be undone, see `GpgAgent::set_confirm_hook()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Destructive<'a> {
+    /// Delete the key with this keygrip
+    DeleteKey(&'a [u8]),
+    /// Stop the agent
+    KillAgent,
+    /// Generate a new card key, replacing the one in this slot
+    ReplaceCardKey(&'a [u8]),
+    /// Reset the OpenPGP card with this serial number
+    CardFactoryReset(&'a str),
+    /// Set the touch policy of this card key slot to `TouchPolicy::Fixed`
+    FixTouchPolicy(u8),
 }
 
 impl GpgAgent<UnixStream, UnixStream> {
     /// Try to find the gpg-agent socket in standard paths 
     /// `/run/user/<uid>/gnupg/S.gpg-agent` and `~/.gnupg/S.gpg-agent`.
+    /// There is no Windows version, see the crate documentation.
+    /// Sockets owned by another user are skipped, see
+    /// `from_mounted_socket()` for sockets shared with a container.
     pub fn from_standard_paths() -> Result<Self, GpgAgentError> {
-        // from /run/user/$UID
+        // from /run/user/$UID, then the home folder
         let uid = format!("{}", getuid());
-        let mut path = PathBuf::from("/run/user");
-        path.push(uid);
-        path.push("gnupg");
-        path.push("S.gpg-agent");
-        if let Ok(agent) = Self::from_path(path) {
-            return Ok(agent);
+        let mut candidates = vec![["/run/user", &uid, "gnupg", "S.gpg-agent"].iter().collect::<PathBuf>()];
+        if let Some(home) = env::home_dir() {
+            candidates.push(home.join(".gnupg").join("S.gpg-agent"));
         }
 
-        // home folder
-        if let Some(mut path) = env::home_dir() {
-            path.push(".gnupg");
-            path.push("S.gpg-agent");
-            if let Ok(agent) = Self::from_path(path) {
-                return Ok(agent);
+        let mut failures = Vec::new();
+        for path in candidates {
+            let res = if path.exists() && !owned_by_user(&path) {
+                Err(GpgAgentError::PermissionDenied(path))
+            } else {
+                Self::from_path(path)
+            };
+            match res {
+                Ok(agent) => return Ok(agent),
+                Err(err) => failures.push(err),
             }
         }
 
-        Err(GpgAgentError::SocketNotFound)
+        // Report the first socket that exists, there is something to fix there
+        let mut tried = Vec::new();
+        for err in failures {
+            match err {
+                GpgAgentError::NotFound(paths) => tried.extend(paths),
+                err => return Err(err),
+            }
+        }
+        Err(GpgAgentError::NotFound(tried))
     }
 
-    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Self, AssuanError> {
-        let stream = UnixStream::connect(p)?;
+    /// Connect to the agent for the GnuPG home directory `home`, starting
+    /// it if it is not running. The socket location is given by `gpgconf`,
+    /// since for homes other than the default it is derived from a hash of
+    /// the path.
+    pub fn from_home_dir<P: AsRef<Path>>(home: P) -> Result<Self, GpgAgentError> {
+        let home = home.as_ref();
+        let output = Command::new("gpgconf")
+            .arg("--homedir").arg(home)
+            .args(["--list-dirs", "agent-socket"])
+            .output()
+            .map_err(AssuanError::from)?;
+        if !output.status.success() {
+            return Err(GpgAgentError::SocketNotFound);
+        }
+        let path = PathBuf::from(OsStr::from_bytes(output.stdout.trim_ascii_end()));
+
+        if let Ok(agent) = Self::from_path(&path) {
+            return Ok(agent);
+        }
+        let launched = Command::new("gpgconf")
+            .arg("--homedir").arg(home)
+            .args(["--launch", "gpg-agent"])
+            .status()
+            .map_err(AssuanError::from)?;
+        if !launched.success() {
+            return Err(GpgAgentError::SocketNotFound);
+        }
+        Self::from_path(&path)
+    }
+
+    /// Connect to the agent socket at `p`. On Linux a path starting with a
+    /// NUL byte names a socket in the abstract namespace, e.g.
+    /// `"\0gpg-agent"`.
+    ///
+    /// A `%Assuan%` redirect file in place of the socket is followed to the
+    /// socket it names, `socket_path()` is still `p`.
+    ///
+    /// Failures to connect are told apart as `NotFound`, `NotRunning`,
+    /// `PermissionDenied` and `StaleSocket`.
+    pub fn from_path<P: AsRef<Path>>(p: P) -> Result<Self, GpgAgentError> {
+        let path = helpers::follow_redirect(p.as_ref());
+        let mut agent = Self::connect(&path).map_err(|err| connect_error(&path, err))?;
+        // Go through the redirect again for other connections
+        agent.socket_path = Some(p.as_ref().to_owned());
+        Ok(agent)
+    }
+
+    fn connect(p: &Path) -> Result<Self, AssuanError> {
+        let stream = helpers::connect(p)?;
+        Ok(GpgAgent {
+            client: AssuanClient::new(stream.try_clone()?, stream.try_clone()?)?,
+            socket_path: Some(p.to_owned()),
+            connected_since: SystemTime::now(),
+            transport: TransportKind::UnixSocket,
+            options: Vec::new(),
+            stream: Some(stream),
+            read_timeout: None,
+            interactive_timeout: None,
+            confirm: None,
+            audit: None,
+            metrics: None,
+            _process: None,
+            has_option: HashMap::new(),
+        })
+    }
+
+    /// Connect to an agent socket mounted from the host, e.g. in a Docker
+    /// or Podman container.
+    ///
+    /// Unlike `from_standard_paths()`, the socket does not need to be owned
+    /// by the current user, in a container the uid of the host user is
+    /// usually mapped to another one or to `nobody`. This means whoever
+    /// can replace the socket file can pose as the agent, so only use it
+    /// for paths no other user can write to.
+    ///
+    /// If the socket can not be opened `GpgAgentError::PermissionDenied` is
+    /// returned, whose message says how to fix the uid mapping.
+    pub fn from_mounted_socket<P: AsRef<Path>>(path: P) -> Result<Self, GpgAgentError> {
+        Self::from_path(path)
+    }
+
+    /// Open another connection to the same agent socket, with the options
+    /// and timeouts of this one. The confirmation hook, audit log and
+    /// metrics are shared with it.
+    pub fn duplicate(&self) -> Result<Self, GpgAgentError> {
+        let path = self.socket_path.as_ref().ok_or(GpgAgentError::SocketNotFound)?;
+        let mut agent = Self::from_path(path)?;
+        agent.confirm = self.confirm.clone();
+        agent.audit = self.audit.clone();
+        if let Some(ref metrics) = self.metrics {
+            agent.share_metrics(metrics.clone());
+        }
+        agent.options = self.options.clone();
+        agent.replay_options()?;
+        agent.set_read_timeout(self.read_timeout)?;
+        agent.interactive_timeout = self.interactive_timeout;
+        Ok(agent)
+    }
+}
+
+impl GpgAgent<ChildStdout, ChildStdin> {
+    /// Talk to the agent through the stdin and stdout of `command`, e.g. a
+    /// tunnel to an agent on another machine. Its stderr is inherited.
+    /// Timeouts have no effect on these connections.
+    pub fn from_command(mut command: Command) -> Result<Self, GpgAgentError> {
+        let mut child = command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(AssuanError::from)?;
+        let (stdout, stdin) = (child.stdout.take(), child.stdin.take());
+        let mut process = ChildGuard(child);
+        let client = match AssuanClient::new(stdout.expect("piped stdout"), stdin.expect("piped stdin")) {
+            Ok(client) => client,
+            Err(err) => {
+                let _ = process.0.kill();
+                return Err(err.into());
+            }
+        };
         Ok(GpgAgent {
-            client: AssuanClient::new(stream.try_clone().unwrap(), stream)?
+            client,
+            socket_path: None,
+            connected_since: SystemTime::now(),
+            transport: TransportKind::Command,
+            options: Vec::new(),
+            stream: None,
+            read_timeout: None,
+            interactive_timeout: None,
+            confirm: None,
+            audit: None,
+            metrics: None,
+            _process: Some(process),
+            has_option: HashMap::new(),
         })
     }
+
+    /// Connect to the agent of `host` over SSH, through its extra socket
+    /// or `remote_socket` if given. The host needs `socat`, and SSH must
+    /// log in without asking on the terminal, e.g. with a key.
+    ///
+    /// ```no_run
+    ///     # use gpgagent::GpgAgent;
+    ///     let mut agent = GpgAgent::from_ssh("workstation.example.org", None).unwrap();
+    ///     println!("{}", agent.version().unwrap());
+    /// ```
+    ///
+    /// The extra socket only accepts a restricted set of commands, as for
+    /// agent forwarding. Socket paths with `:`, `,` or `!` need socat
+    /// 1.7.4 or later on the host, which understands escaped addresses.
+    pub fn from_ssh<H: AsRef<OsStr>>(host: H, remote_socket: Option<&str>) -> Result<Self, GpgAgentError> {
+        Self::from_command(ssh_command(host.as_ref(), remote_socket))
+    }
+}
+
+/// The ssh command for `GpgAgent::from_ssh()`
+fn ssh_command(host: &OsStr, remote_socket: Option<&str>) -> Command {
+    let socket = match remote_socket {
+        Some(path) => format!("'{}'", path.replace('\'', "'\\''")),
+        None => "\"$(gpgconf --list-dirs agent-extra-socket)\"".to_owned(),
+    };
+    let mut command = Command::new("ssh");
+    // After --, a host such as -oProxyCommand=... is not taken as an option
+    command.args(["-T", "-o", "BatchMode=yes", "--"])
+        .arg(host)
+        // socat splits its addresses at these characters unless escaped
+        .arg(format!("exec socat - UNIX-CONNECT:\"$(printf '%s' {} | sed 's/[\\\\:,!]/\\\\&/g')\"", socket));
+    command
+}
+
+/// Check a text shown by the pinentry, e.g. a prompt or description.
+/// Newlines are fine, other control characters are rejected.
+fn check_text(what: &str, text: &[u8]) -> Result<(), GpgAgentError> {
+    match text.iter().find(|&&b| b != b'\n' && (b < 0x20 || b == 0x7f)) {
+        Some(b) => Err(GpgAgentError::InvalidText(format!("Invalid control character 0x{:02x} in {}", b, what))),
+        None => Ok(()),
+    }
 }
 
+/// Replace the control characters that the pinentry can not show with
+/// spaces, keeping newlines. Tabs are replaced too.
+pub fn sanitize_text<T: AsRef<[u8]>>(text: T) -> Vec<u8> {
+    text.as_ref().iter()
+        .map(|&b| if b != b'\n' && (b < 0x20 || b == 0x7f) { b' ' } else { b })
+        .collect()
+}
+
+/// Decode a hex encoded passphrase, zeroizing the hex copy
+#[cfg(feature = "secrecy")]
+fn secret_from_hex(mut pass: String) -> Result<SecretVec<u8>, GpgAgentError> {
+    let res = pass.from_hex();
+    pass.zeroize();
+    res.map(SecretVec::new).or(Err(GpgAgentError::InvalidPassword))
+}
+
+/// Arguments are taken as `AsRef<[u8]>`, so `&str`, `String` and byte
+/// slices work alike. The Assuan protocol is byte oriented, and neither
+/// file names nor locale dependent text need to be valid UTF-8.
+///
+/// Option names can also be given as `constants::AgentOption` e.g.
+///
+///     # use gpgagent::GpgAgent;
+///     use gpgagent::constants::AgentOption;
+///     let mut agent = GpgAgent::from_standard_paths().unwrap();
+///     agent.option(AgentOption::LcMessages, "C").unwrap();
+///     agent.option_bool(AgentOption::AllowPinentryNotify, true).unwrap();
 impl<R, W> GpgAgent<R, W> where R: Read, W: Write{
-    pub fn option(&mut self, name: &str, val: &str) -> Result<(), GpgAgentError> {
-        self.client.option(name, val)
-            .map_err(GpgAgentError::from)
+    pub fn option<N, V>(&mut self, name: N, val: V) -> Result<(), GpgAgentError>
+        where N: AsRef<[u8]>, V: AsRef<[u8]>
+    {
+        self.client.option(&name, &val)?;
+        self.record_option(name.as_ref(), val.as_ref());
+        Ok(())
+    }
+
+    /// Set a flag option, see `AssuanClient::option_bool()`. Flags are
+    /// recorded in `options()` with an empty value.
+    pub fn option_bool<N: AsRef<[u8]>>(&mut self, name: N, val: bool) -> Result<(), GpgAgentError> {
+        self.client.option_bool(&name, val)?;
+        if val {
+            self.record_option(name.as_ref(), b"");
+        }
+        Ok(())
+    }
+
+    /// Whether the pinentry grabs the keyboard while it is shown. Not
+    /// grabbing helps with terminal multiplexers and Wayland compositors
+    /// that do not allow it. Older agents fail with "Unknown option".
+    pub fn set_grab(&mut self, grab: bool) -> Result<(), GpgAgentError> {
+        let (name, other) = if grab {
+            (option::GRAB, option::NO_GRAB)
+        } else {
+            (option::NO_GRAB, option::GRAB)
+        };
+        self.client.option_flag(name)?;
+        self.options.retain(|opt| opt.0 != other.as_bytes());
+        self.record_option(name.as_bytes(), b"");
+        Ok(())
+    }
+
+    /// Send the recorded options again, e.g. on a new connection
+    fn replay_options(&mut self) -> Result<(), GpgAgentError> {
+        for (name, val) in &self.options {
+            if val.is_empty() {
+                self.client.option_flag(name)?;
+            } else {
+                self.client.option(name, val)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn record_option(&mut self, name: &[u8], val: &[u8]) {
+        match self.options.iter().position(|opt| opt.0 == name) {
+            Some(idx) => self.options[idx].1 = val.to_vec(),
+            None => self.options.push((name.to_vec(), val.to_vec())),
+        }
+    }
+
+    /// Report every command sent to the agent to `metrics`, see
+    /// `assuan::Metrics`.
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: M) {
+        self.share_metrics(Arc::new(Mutex::new(metrics)));
+    }
+
+    fn share_metrics(&mut self, metrics: Arc<Mutex<dyn Metrics>>) {
+        self.metrics = Some(metrics.clone());
+        self.client.set_metrics(move |command: &str, elapsed, outcome| {
+            metrics.lock().unwrap().record(command, elapsed, outcome)
+        });
+    }
+
+    /// Call `hook` with every status line the agent sends, as it arrives,
+    /// see `AssuanClient::on_status()`.
+    pub fn on_status<F>(&mut self, hook: F) where F: FnMut(&str, &str) + Send + 'static {
+        self.client.on_status(hook);
+    }
+
+    /// Set how INQUIREs from the agent are answered, see
+    /// `AssuanClient::set_inquire_policy()`.
+    pub fn set_inquire_policy(&mut self, policy: InquirePolicy) {
+        self.client.set_inquire_policy(policy);
+    }
+
+    /// Fail with `AssuanError::Timeout` when the agent does not reply
+    /// within `timeout`, `None` waits forever. This does not
+    /// apply to commands that wait for the user, see
+    /// `set_interactive_timeout()`.
+    ///
+    /// After a timeout the reply may still arrive later, so the
+    /// connection should not be used anymore.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), GpgAgentError> {
+        self.apply_timeout(timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Timeout for commands that wait for the user to answer the pinentry,
+    /// e.g. `get_passphrase()` or `get_confirmation()`. By default they
+    /// wait forever, since the user may take minutes to answer.
+    pub fn set_interactive_timeout(&mut self, timeout: Option<Duration>) {
+        self.interactive_timeout = timeout;
+    }
+
+    fn apply_timeout(&self, timeout: Option<Duration>) -> Result<(), GpgAgentError> {
+        match self.stream {
+            Some(ref stream) => stream.set_read_timeout(timeout)
+                .map_err(|err| GpgAgentError::Protocol(err.into())),
+            None => Ok(()),
+        }
+    }
+
+    /// Run a command that waits for the user with the interactive
+    /// timeout, or `timeout` if given, instead of the read timeout
+    fn interactive<T, F>(&mut self, timeout: Option<Duration>, f: F) -> Result<T, GpgAgentError>
+        where F: FnOnce(&mut Self) -> Result<T, GpgAgentError>
+    {
+        let (read_timeout, timeout) = (self.read_timeout, timeout.or(self.interactive_timeout));
+        if timeout == read_timeout {
+            return f(self);
+        }
+        self.apply_timeout(timeout)?;
+        let res = f(self);
+        self.apply_timeout(read_timeout)?;
+        res
+    }
+
+    /// Path of the agent socket, if connected through one.
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+
+    /// When the connection was established.
+    pub fn connected_since(&self) -> SystemTime {
+        self.connected_since
+    }
+
+    /// The kind of connection to the agent.
+    pub fn transport_kind(&self) -> TransportKind {
+        self.transport
+    }
+
+    /// Options (name, value) successfully set with `option()` in this
+    /// session, in the order they were first set.
+    pub fn options(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.options
+    }
+
+    /// The agent version, e.g. "2.4.4".
+    pub fn version(&mut self) -> Result<String, GpgAgentError> {
+        self.getinfo("version")
+    }
+
+    /// A snapshot of the agent state, e.g. for a dashboard.
+    pub fn status(&mut self) -> Result<AgentStatus, GpgAgentError> {
+        let version = self.version()?;
+        let pid = self.getinfo_number("pid")?;
+        // The agent fails the command if scdaemon is not running
+        let scdaemon_running = match self.client.exec("GETINFO", &[b"scd_running"]) {
+            Ok(_) => true,
+            Err(AssuanError::Server { .. }) => false,
+            Err(err) => return Err(err.into()),
+        };
+
+        self.client.exec("KEYINFO", &[b"--list"])?;
+        let keys: Vec<bool> = self.client.status_lines().iter()
+            .filter(|line| line.0 == status::KEYINFO)
+            // KEYINFO <keygrip> <type> <serialno> <idstr> <cached> ...
+            .map(|line| line.1.split(' ').nth(4) == Some("1"))
+            .collect();
+
+        Ok(AgentStatus {
+            socket_path: self.socket_path.clone(),
+            transport: self.transport,
+            connected_since: self.connected_since,
+            version,
+            pid: pid as u32,
+            scdaemon_running,
+            keys: keys.len(),
+            cached_keys: keys.iter().filter(|&&cached| cached).count(),
+            event_counters: self.event_counters()?,
+        })
+    }
+
+    /// The S2K iteration count the agent uses to protect keys, calibrated
+    /// so that hashing takes about 100ms unless configured otherwise.
+    pub fn s2k_count(&mut self) -> Result<u64, GpgAgentError> {
+        self.getinfo_number("s2k_count")
+    }
+
+    /// The S2K iteration count from the agent calibration, regardless of
+    /// the `s2k-count` setting.
+    pub fn s2k_count_calibrated(&mut self) -> Result<u64, GpgAgentError> {
+        self.getinfo_number("s2k_count_cal")
+    }
+
+    /// The time the calibrated S2K count takes to hash.
+    pub fn s2k_time(&mut self) -> Result<Duration, GpgAgentError> {
+        self.getinfo_number("s2k_time").map(Duration::from_millis)
+    }
+
+    /// Whether the agent supports `option` (without the leading `--`) for
+    /// `command`, as reported by `GETINFO cmd_has_option`. Agents only
+    /// report options added after the command, and agents too old for the
+    /// query support none of them. Answers are kept for the connection.
+    pub fn command_has_option(&mut self, command: &str, option: &str) -> Result<bool, GpgAgentError> {
+        let key = (command.to_uppercase(), option.to_owned());
+        if let Some(&has) = self.has_option.get(&key) {
+            return Ok(has);
+        }
+        let has = match self.client.exec("GETINFO", &[b"cmd_has_option", key.0.as_bytes(), option.as_bytes()]) {
+            Ok(_) => true,
+            Err(AssuanError::Server { .. }) => false,
+            Err(err) => return Err(err.into()),
+        };
+        self.has_option.insert(key, has);
+        Ok(has)
+    }
+
+    fn getinfo(&mut self, what: &str) -> Result<String, GpgAgentError> {
+        let data = self.client.exec_data("GETINFO", &[what.as_bytes()])?;
+        String::from_utf8(data)
+            .map_err(|err| AssuanError::UnexpectedResponse(err.into_bytes()).into())
+    }
+
+    fn getinfo_number(&mut self, what: &str) -> Result<u64, GpgAgentError> {
+        let val = self.getinfo(what)?;
+        val.trim().parse()
+            .map_err(|_| AssuanError::Other(format!("Invalid GETINFO {} reply: {}", what, val)).into())
     }
 
     pub fn update_startup_tty(&mut self) -> Result<(), GpgAgentError> {
@@ -101,19 +803,455 @@ impl<R, W> GpgAgent<R, W> where R: Read, W: Write{
             .map(|_| ())
     }
 
-    pub fn get_passphrase(&mut self, cache_id: &str, error_message: &str, prompt: &str, description: &str) -> Result<Vec<u8>, GpgAgentError> {
-        let pass = self.client.exec("GET_PASSPHRASE",
-                                         &[cache_id.as_bytes(), error_message.as_bytes(), prompt.as_bytes(), description.as_bytes()])
-            .map(|res| res.0)?;
+    /// Make the agent reload its configuration with RELOADAGENT, then wait
+    /// up to `timeout` until it replies to NOP again and set the options
+    /// of this session once more, see `options()`.
+    pub fn reload_and_wait(&mut self, timeout: Duration) -> Result<(), GpgAgentError> {
+        self.client.exec("RELOADAGENT", &[])?;
+
+        let deadline = Instant::now() + timeout;
+        while let Err(err) = self.client.nop() {
+            if Instant::now() >= deadline {
+                return Err(err.into());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        self.replay_options()
+    }
+
+    pub fn get_passphrase<C, E, P, D>(&mut self, cache_id: C, error_message: E, prompt: P, description: D) -> Result<Vec<u8>, GpgAgentError>
+        where C: AsRef<[u8]>, E: AsRef<[u8]>, P: AsRef<[u8]>, D: AsRef<[u8]>
+    {
+        let pass = self.get_passphrase_hex(&[], &[cache_id.as_ref(), error_message.as_ref(), prompt.as_ref(), description.as_ref()], None)?;
         pass.from_hex().or(Err(GpgAgentError::InvalidPassword))
     }
 
-    pub fn clear_passphrase(&mut self, cache_id: &str) -> Result<(), GpgAgentError> {
-        self.client.exec("CLEAR_PASSPHRASE", &[cache_id.as_bytes()])
+    /// Same as `get_passphrase()` but the passphrase is returned as a
+    /// `secrecy::SecretVec`, and the intermediate hex encoded copy is
+    /// zeroized.
+    #[cfg(feature = "secrecy")]
+    pub fn get_passphrase_secret<C, E, P, D>(&mut self, cache_id: C, error_message: E, prompt: P, description: D) -> Result<SecretVec<u8>, GpgAgentError>
+        where C: AsRef<[u8]>, E: AsRef<[u8]>, P: AsRef<[u8]>, D: AsRef<[u8]>
+    {
+        let pass = self.get_passphrase_hex(&[], &[cache_id.as_ref(), error_message.as_ref(), prompt.as_ref(), description.as_ref()], None)?;
+        secret_from_hex(pass)
+    }
+
+    /// GET_PASSPHRASE with flags (e.g. `--repeat`), cache id, error
+    /// message, prompt and description. `timeout` overrides the
+    /// interactive timeout.
+    fn get_passphrase_hex(&mut self, flags: &[&[u8]], args: &[&[u8]; 4], timeout: Option<Duration>)
+        -> Result<String, GpgAgentError>
+    {
+        check_text("error message", args[1])?;
+        check_text("prompt", args[2])?;
+        check_text("description", args[3])?;
+        let cache_id = Some(args[0]).filter(|&id| id != b"X");
+        let args: Vec<&[u8]> = flags.iter().chain(args.iter()).cloned().collect();
+        self.audited("GET_PASSPHRASE", cache_id, |agent| {
+            agent.interactive(timeout, |agent| {
+                agent.client.exec("GET_PASSPHRASE", &args)
+                    .map(|res| res.message)
+                    .map_err(GpgAgentError::from)
+            })
+        })
+    }
+
+    /// Whether a passphrase is cached under `cache_id`, as by
+    /// `get_passphrase()`. This never prompts.
+    pub fn is_cached<C: AsRef<[u8]>>(&mut self, cache_id: C) -> Result<bool, GpgAgentError> {
+        self.audited("GET_PASSPHRASE --no-ask", Some(cache_id.as_ref()), |agent| {
+            match agent.client.exec("GET_PASSPHRASE", &[b"--no-ask", cache_id.as_ref(), b"X", b"X", b"X"]) {
+                Ok(_) => Ok(true),
+                Err(err) if err.error_code() == Some(error::NO_DATA) => Ok(false),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    pub fn clear_passphrase<C: AsRef<[u8]>>(&mut self, cache_id: C) -> Result<(), GpgAgentError> {
+        self.audited("CLEAR_PASSPHRASE", Some(cache_id.as_ref()), |agent| {
+            agent.client.exec("CLEAR_PASSPHRASE", &[cache_id.as_ref()])
+                .map_err(GpgAgentError::from)
+                .map(|_| ())
+        })
+    }
+
+    /// Store a passphrase in the agent cache for the given keygrip, without
+    /// prompting the user. The entry expires after `ttl` seconds, or uses the
+    /// agent default if `None`.
+    ///
+    /// The agent must be started with `--allow-preset-passphrase`. Note that
+    /// current agents only implement the default ttl.
+    pub fn preset_passphrase<K: AsRef<[u8]>>(&mut self, keygrip: K, ttl: Option<u32>, passphrase: &[u8]) -> Result<(), GpgAgentError> {
+        let ttl = ttl.map_or("-1".to_owned(), |ttl| ttl.to_string());
+        self.audited("PRESET_PASSPHRASE", Some(keygrip.as_ref()), |agent| {
+            agent.client.exec("PRESET_PASSPHRASE",
+                              &[keygrip.as_ref(), ttl.as_bytes(), passphrase.to_hex().as_bytes()])
+                .map_err(GpgAgentError::from)
+                .map(|_| ())
+        })
+    }
+
+    /// Ask for the passphrase of the key with this keygrip, showing `desc`,
+    /// and keep it in the agent cache with `preset_passphrase()`. Later
+    /// operations on the key then need no prompt, e.g. in a batch signing
+    /// job. The passphrase is not checked against the key here.
+    pub fn unlock_key<K: AsRef<[u8]>>(&mut self, keygrip: K, desc: &KeyDescription) -> Result<(), GpgAgentError> {
+        let passphrase = PassphrasePrompt::new(desc.to_string())
+            .prompt("Passphrase:")
+            .ask(self)?;
+        self.preset_passphrase(keygrip, None, &passphrase)
+    }
+
+    /// Remove a passphrase set with `preset_passphrase()` from the cache.
+    pub fn forget_passphrase<K: AsRef<[u8]>>(&mut self, keygrip: K) -> Result<(), GpgAgentError> {
+        self.audited("CLEAR_PASSPHRASE --mode=normal", Some(keygrip.as_ref()), |agent| {
+            agent.client.exec("CLEAR_PASSPHRASE", &[b"--mode=normal", keygrip.as_ref()])
+                .map_err(GpgAgentError::from)
+                .map(|_| ())
+        })
+    }
+
+    /// Call `hook` before every destructive command, which is only sent if
+    /// it returns true. Otherwise the command fails with
+    /// `GpgAgentError::Denied`. This comes on top of any confirmation the
+    /// agent asks itself, and can be used to forbid those commands.
+    ///
+    ///     # use gpgagent::{GpgAgent, Destructive};
+    ///     let mut agent = GpgAgent::from_standard_paths().unwrap();
+    ///     agent.set_confirm_hook(|cmd| cmd != Destructive::KillAgent);
+    ///     assert!(agent.kill_agent().is_err());
+    pub fn set_confirm_hook<F>(&mut self, hook: F)
+        where F: FnMut(Destructive) -> bool + Send + 'static
+    {
+        self.confirm = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Record every operation in `log`, with the keygrip or cache id it
+    /// was about but never any secret. Connections opened by `duplicate()`
+    /// write to the same log.
+    ///
+    /// ```no_run
+    ///     # use gpgagent::{AuditWriter, GpgAgent};
+    ///     # use std::fs::OpenOptions;
+    ///     let mut agent = GpgAgent::from_standard_paths().unwrap();
+    ///     let file = OpenOptions::new().create(true).append(true).open("/var/log/agent-audit.log").unwrap();
+    ///     agent.set_audit_log(AuditWriter(file));
+    /// ```
+    pub fn set_audit_log<A: AuditSink + 'static>(&mut self, log: A) {
+        self.audit = Some(Arc::new(Mutex::new(log)));
+    }
+
+    /// Run `f` and record it in the audit log
+    fn audited<T, F>(&mut self, operation: &str, subject: Option<&[u8]>, f: F) -> Result<T, GpgAgentError>
+        where F: FnOnce(&mut Self) -> Result<T, GpgAgentError>
+    {
+        let res = f(self);
+        if let Some(ref log) = self.audit {
+            let subject = subject.map(String::from_utf8_lossy);
+            log.lock().unwrap().record(&AuditEvent {
+                time: SystemTime::now(),
+                operation,
+                subject: subject.as_deref(),
+                outcome: AuditEvent::outcome_of(&res),
+            });
+        }
+        res
+    }
+
+    fn confirm(&mut self, cmd: Destructive) -> Result<(), GpgAgentError> {
+        if let Some(ref hook) = self.confirm {
+            if !(*hook.lock().unwrap())(cmd) {
+                return Err(GpgAgentError::Denied);
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the secret key with this keygrip. The agent asks the user to
+    /// confirm through the pinentry.
+    pub fn delete_key<K: AsRef<[u8]>>(&mut self, keygrip: K) -> Result<(), GpgAgentError> {
+        self.audited("DELETE_KEY", Some(keygrip.as_ref()), |agent| {
+            agent.confirm(Destructive::DeleteKey(keygrip.as_ref()))?;
+            agent.interactive(None, |agent| {
+                agent.client.exec("DELETE_KEY", &[keygrip.as_ref()])
+                    .map_err(GpgAgentError::from)
+                    .map(|_| ())
+            })
+        })
+    }
+
+    /// Stop the agent. It is started again by the next program that needs
+    /// it, but this connection can not be used anymore.
+    pub fn kill_agent(&mut self) -> Result<(), GpgAgentError> {
+        self.audited("KILLAGENT", None, |agent| {
+            agent.confirm(Destructive::KillAgent)?;
+            agent.client.exec("KILLAGENT", &[])
+                .map_err(GpgAgentError::from)
+                .map(|_| ())
+        })
+    }
+
+    /// Ask the user to confirm `description` through the pinentry. Returns an
+    /// error if the user does not confirm.
+    pub fn get_confirmation<D: AsRef<[u8]>>(&mut self, description: D) -> Result<(), GpgAgentError> {
+        check_text("description", description.as_ref())?;
+        self.interactive(None, |agent| {
+            agent.client.exec("GET_CONFIRMATION", &[description.as_ref()])
+                .map_err(GpgAgentError::from)
+                .map(|_| ())
+        })
+    }
+
+    /// Set the description shown by the pinentry if the next command
+    /// needs the passphrase of a key, see `KeyDescription`.
+    pub fn set_key_description<D: AsRef<[u8]>>(&mut self, description: D) -> Result<(), GpgAgentError> {
+        check_text("description", description.as_ref())?;
+        self.client.exec("SETKEYDESC", &[description.as_ref()])
             .map_err(GpgAgentError::from)
             .map(|_| ())
     }
 
+    /// Read the agent event counters.
+    pub fn event_counters(&mut self) -> Result<EventCounters, GpgAgentError> {
+        self.client.exec("GETEVENTCOUNTER", &[])?;
+        let counters = self.client.status_lines().iter()
+            .find(|line| line.0 == status::EVENTCOUNTER)
+            .map(|line| line.1.split(' ')
+                 .map(|n| n.parse().unwrap_or(0))
+                 .collect::<Vec<u64>>())
+            .unwrap_or_default();
+
+        Ok(EventCounters {
+            any: counters.first().cloned().unwrap_or(0),
+            key: counters.get(1).cloned().unwrap_or(0),
+            card: counters.get(2).cloned().unwrap_or(0),
+        })
+    }
+
+    /// Serial number of the inserted smartcard, this fails if no card is
+    /// present.
+    pub fn card_serialno(&mut self) -> Result<String, GpgAgentError> {
+        self.client.exec("SCD", &[b"SERIALNO"])?;
+        self.client.status_lines().iter()
+            .find(|line| line.0 == status::SERIALNO)
+            .map(|line| line.1.split(' ').next().unwrap_or("").to_owned())
+            .ok_or_else(|| AssuanError::Other("Missing SERIALNO status".to_owned()).into())
+    }
+
+    /// The names of the card readers scdaemon found. Older scdaemons only
+    /// list the readers of their internal CCID driver, not PC/SC ones.
+    ///
+    /// scdaemon does not report a state per reader, so only the names are
+    /// returned. `card_state()` tells a missing reader from a missing card
+    /// for the reader scdaemon uses.
+    pub fn list_readers(&mut self) -> Result<Vec<String>, GpgAgentError> {
+        let data = self.client.exec_data("SCD", &[b"GETINFO", b"reader_list"])?;
+        Ok(String::from_utf8_lossy(&data).lines()
+           .filter(|name| !name.is_empty())
+           .map(|name| name.to_owned())
+           .collect())
+    }
+
+    /// Whether a card reader and card are present, to tell the user what
+    /// is missing before a card operation.
+    pub fn card_state(&mut self) -> Result<CardState, GpgAgentError> {
+        match self.client.exec("SCD", &[b"SERIALNO"]) {
+            Ok(_) => Ok(CardState::Present),
+            Err(err) => match err.error_code() {
+                Some(error::CARD_NOT_PRESENT) | Some(error::CARD_REMOVED) => Ok(CardState::NoCard),
+                Some(error::ENODEV) => Ok(CardState::NoReader),
+                _ => Err(err.into()),
+            },
+        }
+    }
+
+    /// Sign `digest` with the card key `keyref` (e.g. "OPENPGP.1"), where
+    /// `hash` names the algorithm of the digest (e.g. "sha256"). The agent
+    /// asks for the card PIN through the pinentry if needed.
+    pub fn card_sign<K, H>(&mut self, keyref: K, hash: H, digest: &[u8]) -> Result<Vec<u8>, GpgAgentError>
+        where K: AsRef<[u8]>, H: AsRef<[u8]>
+    {
+        let mut hash_arg = b"--hash=".to_vec();
+        hash_arg.extend_from_slice(hash.as_ref());
+        self.card_operation(digest, &[b"PKSIGN", &hash_arg, keyref.as_ref()])
+    }
+
+    /// Decrypt `ciphertext` with the card key `keyref` (e.g. "OPENPGP.2").
+    /// For RSA keys this is the raw ciphertext, for ECDH the ephemeral
+    /// public point. The result is returned as it comes from the card.
+    pub fn card_decrypt<K: AsRef<[u8]>>(&mut self, keyref: K, ciphertext: &[u8]) -> Result<Vec<u8>, GpgAgentError> {
+        self.card_operation(ciphertext, &[b"PKDECRYPT", keyref.as_ref()])
+    }
+
+    /// Sign `challenge` with the card authentication key `keyref` (e.g.
+    /// "OPENPGP.3"), as done for SSH logins.
+    pub fn card_auth<K: AsRef<[u8]>>(&mut self, keyref: K, challenge: &[u8]) -> Result<Vec<u8>, GpgAgentError> {
+        self.card_operation(challenge, &[b"PKAUTH", keyref.as_ref()])
+    }
+
+    /// Generate a new key on the card in slot `keyref` (e.g. "OPENPGP.1"),
+    /// the agent asks for the admin PIN. An existing key in the slot is
+    /// only replaced with `force`, which also asks the confirmation hook.
+    /// `timestamp` is the creation time, in seconds since the epoch, it
+    /// defaults to the current time.
+    pub fn card_genkey<K>(&mut self, keyref: K, force: bool, timestamp: Option<u64>)
+        -> Result<CardKey, GpgAgentError>
+        where K: AsRef<[u8]>
+    {
+        let mut args: Vec<&[u8]> = vec![b"GENKEY"];
+        if force {
+            args.push(b"--force");
+        }
+        let timestamp = timestamp.map(|secs| format!("--timestamp={}", keydesc::iso_time(secs)));
+        if let Some(ref timestamp) = timestamp {
+            args.push(timestamp.as_bytes());
+        }
+        args.push(keyref.as_ref());
+        self.audited("SCD GENKEY", Some(keyref.as_ref()), |agent| {
+            if force {
+                agent.confirm(Destructive::ReplaceCardKey(keyref.as_ref()))?;
+            }
+            agent.interactive(None, |agent| agent.client.exec("SCD", &args).map_err(GpgAgentError::from))
+        })?;
+
+        let mut key = CardKey::default();
+        for (keyword, line) in self.client.status_lines() {
+            let mut args = line.split(' ');
+            match keyword.as_str() {
+                status::KEY_FPR => key.fingerprint = args.next().unwrap_or("").to_owned(),
+                status::KEY_CREATED_AT => key.created = args.next().and_then(|secs| secs.parse().ok()),
+                status::KEY_DATA => if let (Some(name), Some(value)) = (args.next(), args.next()) {
+                    let value = value.from_hex().or(Err(AssuanError::Other("Invalid KEY-DATA".to_owned())))?;
+                    key.public_key.push((name.to_owned(), value));
+                },
+                _ => (),
+            }
+        }
+        if key.fingerprint.is_empty() {
+            return Err(AssuanError::Other("Missing KEY-FPR status".to_owned()).into());
+        }
+        Ok(key)
+    }
+
+    /// Reset the OpenPGP application of the card to factory state, this
+    /// deletes all its keys and sets the PINs to their defaults. It is
+    /// the same sequence `gpg --card-edit` uses for `factory-reset`.
+    ///
+    /// `serialno` must be the serial number of the inserted card, as
+    /// returned by `card_serialno()`, so a different card is never reset
+    /// by mistake. The confirmation hook is asked as well.
+    pub fn card_factory_reset(&mut self, serialno: &str) -> Result<(), GpgAgentError> {
+        self.audited("SCD factory-reset", Some(serialno.as_bytes()), |agent| {
+            agent.client.exec("SCD", &[b"RESET"])?;
+            if agent.card_serialno()? != serialno {
+                return Err(GpgAgentError::Denied);
+            }
+            agent.confirm(Destructive::CardFactoryReset(serialno))?;
+
+            // The card only accepts TERMINATE DF once both PINs are blocked,
+            // so verify each with a wrong PIN until it is
+            for pin in &[b"81", b"83"] {
+                let verify = [b"002000", &pin[..], b"084040404040404040"].concat();
+                for _ in 0..4 {
+                    let _ = agent.client.exec("SCD", &[b"APDU", &verify]);
+                }
+            }
+            for apdu in &[b"00e60000", b"00440000"] {
+                let resp = agent.client.exec_data("SCD", &[b"APDU", &apdu[..]])?;
+                if !resp.ends_with(&[0x90, 0x00]) {
+                    return Err(AssuanError::Other(format!("Card reset failed, status {}",
+                                                          resp[resp.len().saturating_sub(2)..].to_hex())).into());
+                }
+            }
+            agent.client.exec("SCD", &[b"RESET"])?;
+            agent.card_serialno().map(|_| ())
+        })
+    }
+
+    /// Turn on PIN hashing on the card by writing its KDF data object,
+    /// like `kdf-setup` of `gpg --card-edit`. The agent asks for the admin
+    /// PIN. `user_pin` and `admin_pin` are the PINs of the card, usually
+    /// still the defaults; they are hashed with the S2K count of the agent,
+    /// see `kdf_data()`.
+    pub fn card_setup_kdf(&mut self, user_pin: &[u8], admin_pin: &[u8], single_salt: bool)
+        -> Result<(), GpgAgentError>
+    {
+        let count = self.s2k_count()?;
+        let data = kdf_data(user_pin, admin_pin, count, single_salt).map_err(AssuanError::IoError)?;
+        self.audited("SCD SETATTR", Some(b"KDF"), |agent| {
+            agent.interactive(None, |agent| {
+                agent.client.exec("SCD", &[b"SETATTR", b"KDF", &data])
+                    .map_err(GpgAgentError::from)
+                    .map(|_| ())
+            })
+        })
+    }
+
+    /// The touch policy of the card key slots 1 (sign), 2 (decrypt) and 3
+    /// (authenticate), `None` for slots the card does not report.
+    pub fn card_touch_policy(&mut self) -> Result<[Option<TouchPolicy>; 3], GpgAgentError> {
+        self.client.exec("SCD", &[b"GETATTR", b"UIF"])?;
+        let mut policy = [None; 3];
+        for (keyword, value) in self.client.status_lines() {
+            let slot = match keyword.as_str() {
+                "UIF-1" => 0,
+                "UIF-2" => 1,
+                "UIF-3" => 2,
+                _ => continue,
+            };
+            policy[slot] = assuan::unescape_data(value.as_bytes()).first()
+                .and_then(|&b| TouchPolicy::from_byte(b));
+        }
+        Ok(policy)
+    }
+
+    /// Set the touch policy of card key `slot`, 1 to 3 as in
+    /// `card_touch_policy()`. The agent asks for the admin PIN, and
+    /// `TouchPolicy::Fixed` asks the confirmation hook.
+    pub fn card_set_touch_policy(&mut self, slot: u8, policy: TouchPolicy) -> Result<(), GpgAgentError> {
+        if !(1..=3).contains(&slot) {
+            return Err(AssuanError::Other(format!("Invalid key slot {}", slot)).into());
+        }
+        let name = format!("UIF-{}", slot);
+        // The second byte marks the button as the touch sensor
+        let value = [policy.to_byte(), 0x20];
+        self.audited("SCD SETATTR", Some(name.as_bytes()), |agent| {
+            if policy == TouchPolicy::Fixed {
+                agent.confirm(Destructive::FixTouchPolicy(slot))?;
+            }
+            agent.interactive(None, |agent| {
+                agent.client.exec("SCD", &[b"SETATTR", name.as_bytes(), &value])
+                    .map_err(GpgAgentError::from)
+                    .map(|_| ())
+            })
+        })
+    }
+
+    /// Send `data` with SETDATA and run the SCD command `args`, which may
+    /// ask for the PIN, returning its data
+    fn card_operation(&mut self, data: &[u8], args: &[&[u8]]) -> Result<Vec<u8>, GpgAgentError> {
+        let operation = format!("SCD {}", String::from_utf8_lossy(args[0]));
+        self.audited(&operation, args.last().cloned(), |agent| {
+            // The SCD arguments are passed on to scdaemon as they are. Long
+            // data such as RSA ciphertexts does not fit in one line, so it
+            // is sent in parts with --append.
+            for (i, chunk) in data.chunks(SETDATA_CHUNK).enumerate() {
+                let hex = chunk.to_hex();
+                if i == 0 {
+                    agent.client.exec("SCD", &[b"SETDATA", hex.as_bytes()])?;
+                } else {
+                    agent.client.exec("SCD", &[b"SETDATA", b"--append", hex.as_bytes()])?;
+                }
+            }
+            agent.interactive(None, |agent| {
+                agent.client.exec_data("SCD", args).map_err(GpgAgentError::from)
+            })
+        })
+    }
+
     /// Try to set the ttyname to the current tty, using the POSIX ttyname()
     /// function.
     ///
@@ -128,17 +1266,34 @@ impl<R, W> GpgAgent<R, W> where R: Read, W: Write{
     #[cfg(unix)]
     pub fn setopt_ttyname(&mut self) -> Result<(), GpgAgentError> {
         if let Some(name) = get_ttyname() {
-            self.option("ttyname", &name)
+            self.option(option::TTYNAME, name)
         } else {
             Ok(())
         }
     }
+
+    /// Set `lc-ctype` and `lc-messages` from the locale environment
+    /// variables of this process, so the pinentry uses the same language
+    /// and encoding. Unset locales are left alone.
+    pub fn setopt_locale(&mut self) -> Result<(), GpgAgentError> {
+        if let Some(ctype) = get_locale("LC_CTYPE") {
+            self.option(option::LC_CTYPE, ctype)?;
+        }
+        if let Some(messages) = get_locale("LC_MESSAGES") {
+            self.option(option::LC_MESSAGES, messages)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
     use std::process::{Stdio, Command};
+    // The test agent is built against the library, not this test build,
+    // so only its socket path is used
+    use gpgagent_testutil::TestAgent;
 
     #[test]
     fn gpg_agent() {
@@ -154,16 +1309,315 @@ mod tests {
 
     #[test]
     fn gpg_agent_socket() {
+        let test_agent = TestAgent::new().unwrap();
+        let stream = UnixStream::connect(test_agent.socket_path()).unwrap();
+        assert!(AssuanClient::new(stream.try_clone().unwrap(), stream).is_ok())
+    }
 
-        let mut cmd = Command::new("gpg-agent")
-            .arg("--daemon")
-            .output()
-            .unwrap();
+    #[test]
+    fn text_checks() {
+        assert!(check_text("prompt", b"Line 1\nLine 2").is_ok());
+        assert!(check_text("prompt", b"Bell\x07").is_err());
+        assert!(check_text("prompt", b"Tab\t").is_err());
+        assert_eq!(sanitize_text("a\tb\r\nc\x7f"), b"a b \nc ");
+    }
 
-        let uid = users::get_current_uid();
-        let path = format!("/run/user/{}/gnupg/S.gpg-agent", uid);
-        let stream = UnixStream::connect(path).unwrap();
-        assert!(AssuanClient::new(stream.try_clone().unwrap(), stream).is_ok())
+    #[test]
+    fn key_description() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        let desc = KeyDescription {
+            user_id: "Alice <alice@example.org>".to_owned(),
+            bits: 3072,
+            algorithm: "RSA".to_owned(),
+            key_id: "0123456789ABCDEF".to_owned(),
+            created: 1577836800,
+            main_key_id: None,
+        };
+        agent.set_key_description(desc.to_string()).unwrap();
+
+        let keygrip = "0123456789ABCDEF0123456789ABCDEF01234567";
+        agent.unlock_key(keygrip, &desc).unwrap();
+        agent.forget_passphrase(keygrip).unwrap();
+    }
+
+    #[test]
+    fn s2k_info() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        assert!(agent.s2k_count().unwrap() >= 65536);
+        assert!(agent.s2k_count_calibrated().unwrap() >= 65536);
+        assert!(agent.s2k_time().unwrap() > Duration::from_millis(0));
+    }
+
+    #[test]
+    fn confirm_hook() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        let keygrip = "0123456789ABCDEF0123456789ABCDEF01234567";
+        agent.set_confirm_hook(move |cmd| cmd != Destructive::DeleteKey(keygrip.as_bytes()));
+
+        match agent.delete_key(keygrip) {
+            Err(GpgAgentError::Denied) => (),
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+        // Not denied, but there is no such key
+        assert!(agent.delete_key("1123456789ABCDEF0123456789ABCDEF01234567").unwrap_err().to_string()
+                .contains("No secret key"));
+        agent.kill_agent().unwrap();
+    }
+
+    #[test]
+    fn card_sign_without_card() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        assert!(agent.card_sign("OPENPGP.1", "sha256", &[0; 32]).is_err());
+        assert_ne!(agent.card_state().ok(), Some(CardState::Present));
+        assert!(agent.card_auth("OPENPGP.3", b"challenge").is_err());
+        assert!(agent.card_decrypt("OPENPGP.2", &[0xff; 512]).is_err());
+        assert!(agent.card_genkey("OPENPGP.1", false, Some(0)).is_err());
+
+        agent.set_confirm_hook(|_| false);
+        match agent.card_genkey("OPENPGP.1", true, None) {
+            Err(GpgAgentError::Denied) => (),
+            res => panic!("{:?}", res),
+        }
+        assert!(agent.card_factory_reset("D2760001240102010005000012340000").is_err());
+        assert!(agent.card_setup_kdf(b"123456", b"12345678", false).is_err());
+        assert!(agent.card_touch_policy().is_err());
+        assert!(agent.card_set_touch_policy(4, TouchPolicy::On).is_err());
+        match agent.card_set_touch_policy(1, TouchPolicy::Fixed) {
+            Err(GpgAgentError::Denied) => (),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn connection_metadata() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        assert!(agent.socket_path().unwrap().ends_with("S.gpg-agent"));
+        assert_eq!(agent.transport_kind(), TransportKind::UnixSocket);
+        assert!(agent.version().unwrap().starts_with('2'));
+
+        agent.option("lc-messages", "C").unwrap();
+        agent.option("lc-messages", "C.UTF-8").unwrap();
+        assert_eq!(agent.options(), &[(b"lc-messages".to_vec(), b"C.UTF-8".to_vec())]);
+    }
+
+    #[test]
+    fn mounted_socket() {
+        let test_agent = TestAgent::new().unwrap();
+        assert!(owned_by_user(test_agent.socket_path()));
+        let mut agent = GpgAgent::from_mounted_socket(test_agent.socket_path()).unwrap();
+        assert!(agent.version().unwrap().starts_with('2'));
+
+        let err = GpgAgentError::PermissionDenied(test_agent.socket_path().to_owned());
+        assert!(err.to_string().contains("--userns=keep-id"));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn redirect_file() {
+        let test_agent = TestAgent::new().unwrap();
+        let redirect = test_agent.home().join("S.redirect");
+        fs::write(&redirect, format!("%Assuan%\nsocket={}\n", test_agent.socket_path().display())).unwrap();
+        let mut agent = GpgAgent::from_path(&redirect).unwrap();
+        assert!(agent.version().unwrap().starts_with('2'));
+        assert_eq!(agent.socket_path(), Some(redirect.as_path()));
+        agent.duplicate().unwrap();
+
+        let socket = test_agent.socket_path();
+        env::set_var("GPGAGENT_TEST_REDIRECT", socket.parent().unwrap());
+        let name = socket.file_name().unwrap().to_str().unwrap();
+        fs::write(&redirect, format!("%Assuan%\nsocket=${{GPGAGENT_TEST_REDIRECT}}/{}\n", name)).unwrap();
+        GpgAgent::from_path(&redirect).unwrap();
+
+        fs::write(&redirect, "%Assuan%\nsocket=/nonexistent/S.gpg-agent\n").unwrap();
+        match GpgAgent::from_path(&redirect) {
+            Err(GpgAgentError::NotFound(paths)) => assert_eq!(paths, [PathBuf::from("/nonexistent/S.gpg-agent")]),
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn error_chain() {
+        use std::error::Error;
+
+        let err = GpgAgentError::from(AssuanError::from(io::Error::new(io::ErrorKind::BrokenPipe, "closed")));
+        let io_err = err.source().and_then(Error::source).unwrap();
+        assert_eq!(io_err.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::BrokenPipe);
+        assert!(GpgAgentError::Denied.source().is_none());
+    }
+
+    #[test]
+    fn connect_failures() {
+        let test_agent = TestAgent::new().unwrap();
+        let dir = test_agent.home();
+
+        let missing = dir.join("S.missing");
+        match GpgAgent::from_path(&missing) {
+            Err(GpgAgentError::NotFound(paths)) => assert_eq!(paths, [missing]),
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+
+        let closed = dir.join("S.closed");
+        drop(UnixListener::bind(&closed).unwrap());
+        match GpgAgent::from_path(&closed) {
+            Err(GpgAgentError::NotRunning(path)) => assert_eq!(path, closed),
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+
+        let file = dir.join("S.file");
+        std::fs::write(&file, b"").unwrap();
+        match GpgAgent::from_path(&file) {
+            Err(err @ GpgAgentError::StaleSocket(_)) => assert!(err.to_string().contains("S.file")),
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn command_options() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        assert!(agent.command_has_option("GET_PASSPHRASE", "repeat").unwrap());
+        assert!(agent.command_has_option("get_passphrase", "repeat").unwrap());
+        assert!(!agent.command_has_option("GET_PASSPHRASE", "no-such-option").unwrap());
+        assert!(!agent.command_has_option("NO_SUCH_COMMAND", "repeat").unwrap());
+    }
+
+    #[test]
+    fn grab() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        // Only recorded when the agent knows the option
+        match agent.set_grab(false) {
+            Ok(()) => {
+                agent.set_grab(true).unwrap();
+                assert_eq!(agent.options(), &[(b"grab".to_vec(), Vec::new())]);
+            }
+            Err(_) => assert!(agent.options().is_empty()),
+        }
+    }
+
+    #[test]
+    fn reload() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        agent.option("lc-messages", "C").unwrap();
+        agent.reload_and_wait(Duration::from_secs(5)).unwrap();
+        assert_eq!(agent.options(), &[(b"lc-messages".to_vec(), b"C".to_vec())]);
+        assert!(agent.version().is_ok());
+    }
+
+    #[test]
+    fn duplicate() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        agent.option("lc-messages", "C").unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let operations = Arc::new(Mutex::new(Vec::new()));
+        {
+            let operations = operations.clone();
+            agent.set_audit_log(move |event: &AuditEvent| operations.lock().unwrap().push(event.operation.to_owned()));
+        }
+        let commands = Arc::new(Mutex::new(0));
+        {
+            let commands = commands.clone();
+            agent.set_metrics(move |_: &str, _, _| *commands.lock().unwrap() += 1);
+        }
+        agent.set_confirm_hook(|cmd| cmd != Destructive::KillAgent);
+
+        let mut other = agent.duplicate().unwrap();
+        assert_eq!(other.options(), agent.options());
+        assert_eq!(other.socket_path(), agent.socket_path());
+        assert!(other.version().is_ok());
+        assert!(*commands.lock().unwrap() > 0);
+        // The hook and the audit log of the original still apply
+        match other.kill_agent() {
+            Err(GpgAgentError::Denied) => (),
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(*operations.lock().unwrap(), ["KILLAGENT"]);
+        drop(agent);
+        assert!(other.version().is_ok());
+    }
+
+    #[test]
+    fn status() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        let status = agent.status().unwrap();
+        assert_eq!(status.socket_path.as_deref(), Some(test_agent.socket_path()));
+        assert!(status.version.starts_with('2'));
+        assert!(status.pid > 0);
+        assert_eq!((status.keys, status.cached_keys), (0, 0));
+
+        // A broken reply about scdaemon is an error, not "not running"
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo OK; read l; echo 'D 2.2.40'; echo OK; read l; echo 'D 42'; echo OK; \
+                             read l; echo bogus; while read l; do echo OK; done"]);
+        let mut agent = GpgAgent::from_command(command).unwrap();
+        assert!(agent.status().is_err());
+    }
+
+    #[test]
+    fn ssh_arguments() {
+        let command = ssh_command(OsStr::new("-oProxyCommand=touch x"), Some("/run/a:b,c'd"));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(&args[..5], ["-T", "-o", "BatchMode=yes", "--", "-oProxyCommand=touch x"]);
+
+        // Run the remote command locally, with echo in place of socat
+        let script = args[5].to_str().unwrap().replace("exec socat -", "echo");
+        let output = Command::new("sh").arg("-c").arg(script).output().unwrap();
+        assert_eq!(output.stdout, b"UNIX-CONNECT:/run/a\\:b\\,c'd\n");
+    }
+
+    #[test]
+    fn command_transport() {
+        let test_agent = TestAgent::new().unwrap();
+        // A stand-in for socat on the other side of an SSH connection
+        let mut tunnel = Command::new("python3");
+        tunnel.args(["-c", "import os, socket, sys, threading\n\
+                            s = socket.socket(socket.AF_UNIX)\n\
+                            s.connect(sys.argv[1])\n\
+                            def up():\n    \
+                                for data in iter(lambda: os.read(0, 4096), b''): s.sendall(data)\n\
+                            threading.Thread(target=up, daemon=True).start()\n\
+                            for data in iter(lambda: s.recv(4096), b''): os.write(1, data)\n\
+                            os._exit(0)\n"])
+            .arg(test_agent.socket_path());
+        let mut agent = GpgAgent::from_command(tunnel).unwrap();
+        assert_eq!(agent.transport_kind(), TransportKind::Command);
+        assert!(agent.version().unwrap().starts_with('2'));
+
+        assert!(GpgAgent::from_command(Command::new("true")).is_err());
+    }
+
+    #[test]
+    fn read_timeout() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert!(agent.version().is_ok());
+    }
+
+    #[test]
+    fn interactive_timeout() {
+        let test_agent = TestAgent::builder().delay(Duration::from_millis(500)).start().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+
+        // The read timeout does not apply while the user thinks
+        agent.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(agent.get_passphrase("X", "X", "X", "X").unwrap(), b"passphrase");
+        assert!(agent.get_confirmation("Continue?").is_ok());
+
+        agent.set_interactive_timeout(Some(Duration::from_millis(100)));
+        match agent.get_passphrase("X", "X", "X", "X") {
+            Err(GpgAgentError::Protocol(AssuanError::Timeout)) => (),
+            res => panic!("{:?}", res),
+        }
     }
 }
 
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use {GpgAgent, GpgAgentError, UnixStream};
+
+/// Connections to the agents of several GnuPG home directories, e.g. for
+/// tools that work on more than one keyring
+///
+///     # use gpgagent::AgentManager;
+///     # use std::env;
+///     let mut agents = AgentManager::new();
+///     let home = env::home_dir().unwrap().join(".gnupg");
+///     println!("{}", agents.get(&home).unwrap().version().unwrap());
+///     agents.disconnect(&home);
+///
+/// Home directories are compared after resolving them to an absolute
+/// path, so different spellings of the same directory share one
+/// connection.
+#[derive(Default)]
+pub struct AgentManager {
+    agents: HashMap<PathBuf, GpgAgent<UnixStream, UnixStream>>,
+}
+
+/// The path used as key for `home`
+fn key(home: &Path) -> PathBuf {
+    fs::canonicalize(home).unwrap_or_else(|_| home.to_owned())
+}
+
+impl AgentManager {
+    pub fn new() -> Self {
+        AgentManager::default()
+    }
+
+    /// The agent for `home`, connecting to it (and starting it, see
+    /// `GpgAgent::from_home_dir()`) on first use.
+    pub fn get<P: AsRef<Path>>(&mut self, home: P) -> Result<&mut GpgAgent<UnixStream, UnixStream>, GpgAgentError> {
+        let key = key(home.as_ref());
+        if !self.agents.contains_key(&key) {
+            let agent = GpgAgent::from_home_dir(&key)?;
+            self.agents.insert(key.clone(), agent);
+        }
+        Ok(self.agents.get_mut(&key).expect("agent was just inserted"))
+    }
+
+    /// Add an agent that was connected by other means, e.g. through the
+    /// socket of another user. Returns the agent previously kept for
+    /// `home`, if any.
+    pub fn insert<P: AsRef<Path>>(&mut self, home: P, agent: GpgAgent<UnixStream, UnixStream>)
+        -> Option<GpgAgent<UnixStream, UnixStream>>
+    {
+        self.agents.insert(key(home.as_ref()), agent)
+    }
+
+    /// Whether there is a connection for `home`.
+    pub fn contains<P: AsRef<Path>>(&self, home: P) -> bool {
+        self.agents.contains_key(&key(home.as_ref()))
+    }
+
+    /// The home directories with a connection.
+    pub fn homes(&self) -> impl Iterator<Item = &Path> {
+        self.agents.keys().map(|home| home.as_path())
+    }
+
+    /// Close the connection for `home`, returns false if there was none.
+    /// The agent itself keeps running.
+    pub fn disconnect<P: AsRef<Path>>(&mut self, home: P) -> bool {
+        self.agents.remove(&key(home.as_ref())).is_some()
+    }
+
+    /// Close the connections whose agent no longer replies, e.g. after it
+    /// was killed. Returns the home directories that were dropped.
+    pub fn prune(&mut self) -> Vec<PathBuf> {
+        let dead: Vec<PathBuf> = self.agents.iter_mut()
+            .filter_map(|(home, agent)| agent.version().err().map(|_| home.clone()))
+            .collect();
+        for home in &dead {
+            self.agents.remove(home);
+        }
+        dead
+    }
+
+    /// Close all connections.
+    pub fn clear(&mut self) {
+        self.agents.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.agents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.agents.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{self, Command};
+
+    fn home(name: &str) -> PathBuf {
+        let home = env::temp_dir().join(format!("gpgagent-manager-{}-{}", process::id(), name));
+        fs::create_dir_all(&home).unwrap();
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o700)).unwrap();
+        home
+    }
+
+    fn kill(home: &Path) {
+        Command::new("gpgconf").arg("--homedir").arg(home)
+            .args(["--kill", "gpg-agent"])
+            .status()
+            .unwrap();
+        let _ = fs::remove_dir_all(home);
+    }
+
+    #[test]
+    fn agents_by_home() {
+        let (first, second) = (home("first"), home("second"));
+        let mut agents = AgentManager::new();
+
+        let path = agents.get(&first).unwrap().socket_path().unwrap().to_owned();
+        assert_eq!(agents.get(first.join(".")).unwrap().socket_path(), Some(path.as_path()));
+        assert_eq!(agents.len(), 1);
+        assert!(agents.get(&second).unwrap().socket_path() != Some(path.as_path()));
+        assert_eq!(agents.len(), 2);
+
+        assert!(agents.disconnect(&first));
+        assert!(!agents.contains(&first));
+        assert!(agents.contains(&second));
+        assert!(agents.prune().is_empty());
+
+        let second_key = key(&second);
+        kill(&second);
+        assert_eq!(agents.prune(), vec![second_key]);
+        assert!(agents.is_empty());
+        kill(&first);
+    }
+}
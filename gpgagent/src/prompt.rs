@@ -0,0 +1,161 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use rustc_serialize::hex::FromHex;
+#[cfg(feature = "secrecy")]
+use secrecy::SecretVec;
+
+use {GpgAgent, GpgAgentError};
+
+/// A passphrase request, built step by step instead of passing the
+/// `X` placeholders and flags of GET_PASSPHRASE by hand e.g.
+///
+/// ```no_run
+///     # use gpgagent::{GpgAgent, PassphrasePrompt};
+///     let mut agent = GpgAgent::from_standard_paths().unwrap();
+///     let pin = PassphrasePrompt::new("Unlock the vault")
+///         .prompt("PIN")
+///         .cache_id("vault:pin")
+///         .ask(&mut agent)
+///         .unwrap();
+/// ```
+///
+/// Nothing is cached unless a cache id is given. Texts that are not set
+/// are sent as the `X` placeholder, so a text of just `X` is not shown.
+#[derive(Clone, Debug)]
+pub struct PassphrasePrompt {
+    description: Vec<u8>,
+    prompt: Vec<u8>,
+    error: Vec<u8>,
+    cache_id: Vec<u8>,
+    repeat: bool,
+    quality_bar: bool,
+    timeout: Option<Duration>,
+}
+
+/// The text, or the `X` placeholder if empty
+fn or_placeholder(text: &[u8]) -> &[u8] {
+    if text.is_empty() { b"X" } else { text }
+}
+
+impl PassphrasePrompt {
+    /// A prompt showing `description`, the main text of the pinentry.
+    pub fn new<D: AsRef<[u8]>>(description: D) -> Self {
+        PassphrasePrompt {
+            description: description.as_ref().to_vec(),
+            prompt: Vec::new(),
+            error: Vec::new(),
+            cache_id: Vec::new(),
+            repeat: false,
+            quality_bar: false,
+            timeout: None,
+        }
+    }
+
+    /// The label next to the input field, e.g. "PIN".
+    pub fn prompt<P: AsRef<[u8]>>(mut self, prompt: P) -> Self {
+        self.prompt = prompt.as_ref().to_vec();
+        self
+    }
+
+    /// An error shown above the description, e.g. after a wrong
+    /// passphrase was entered.
+    pub fn error<E: AsRef<[u8]>>(mut self, error: E) -> Self {
+        self.error = error.as_ref().to_vec();
+        self
+    }
+
+    /// Cache the passphrase under `cache_id`, and return it from the
+    /// cache without asking if it is already there.
+    pub fn cache_id<C: AsRef<[u8]>>(mut self, cache_id: C) -> Self {
+        self.cache_id = cache_id.as_ref().to_vec();
+        self
+    }
+
+    /// Ask to type the passphrase a second time, e.g. for a new one. This
+    /// is skipped on agents that do not support it.
+    pub fn repeat(mut self, repeat: bool) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Show the pinentry quality bar while typing.
+    pub fn quality_bar(mut self, quality_bar: bool) -> Self {
+        self.quality_bar = quality_bar;
+        self
+    }
+
+    /// How long to wait for the user, instead of the interactive timeout
+    /// of the agent connection.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn ask_hex<R, W>(&self, agent: &mut GpgAgent<R, W>) -> Result<String, GpgAgentError>
+        where R: Read, W: Write
+    {
+        let mut flags: Vec<&[u8]> = Vec::new();
+        // Agents without the option only ask once
+        if self.repeat && agent.command_has_option("GET_PASSPHRASE", "repeat")? {
+            flags.push(b"--repeat=1");
+        }
+        if self.quality_bar {
+            flags.push(b"--qualitybar");
+        }
+        agent.get_passphrase_hex(&flags, &[or_placeholder(&self.cache_id),
+                                           or_placeholder(&self.error),
+                                           or_placeholder(&self.prompt),
+                                           or_placeholder(&self.description)],
+                                 self.timeout)
+    }
+
+    /// Show the prompt and return the passphrase.
+    pub fn ask<R, W>(&self, agent: &mut GpgAgent<R, W>) -> Result<Vec<u8>, GpgAgentError>
+        where R: Read, W: Write
+    {
+        self.ask_hex(agent)?.from_hex().or(Err(GpgAgentError::InvalidPassword))
+    }
+
+    /// Same as `ask()`, but the passphrase is returned as a
+    /// `secrecy::SecretVec`.
+    #[cfg(feature = "secrecy")]
+    pub fn ask_secret<R, W>(&self, agent: &mut GpgAgent<R, W>) -> Result<SecretVec<u8>, GpgAgentError>
+        where R: Read, W: Write
+    {
+        ::secret_from_hex(self.ask_hex(agent)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use gpgagent_testutil::TestAgent;
+
+    #[test]
+    fn ask() {
+        let test_agent = TestAgent::builder().passphrase("1234").start().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        let prompt = PassphrasePrompt::new("New PIN")
+            .prompt("PIN")
+            .repeat(true)
+            .quality_bar(true);
+        assert_eq!(prompt.ask(&mut agent).unwrap(), b"1234");
+    }
+
+    #[test]
+    fn cached() {
+        let test_agent = TestAgent::builder().passphrase("1234").start().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        let prompt = PassphrasePrompt::new("PIN").cache_id("prompt:test");
+        assert!(!agent.is_cached("prompt:test").unwrap());
+        assert_eq!(prompt.ask(&mut agent).unwrap(), b"1234");
+        assert!(agent.is_cached("prompt:test").unwrap());
+
+        // Without its answer file the pinentry cancels every prompt
+        fs::remove_file(test_agent.home().join("pin")).unwrap();
+        assert_eq!(prompt.ask(&mut agent).unwrap(), b"1234");
+        assert!(PassphrasePrompt::new("PIN").ask(&mut agent).is_err());
+    }
+}
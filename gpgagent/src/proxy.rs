@@ -0,0 +1,238 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use assuan::{AssuanProxy, AssuanError};
+
+use helpers;
+use {GpgAgentError, UnixListener, UnixStream};
+
+/// Listen for connections on the socket at `path` and forward each of them
+/// to the agent socket at `agent`, using `proxy` to filter commands. Either
+/// can be an abstract socket, see `GpgAgent::from_path()`.
+///
+/// This can be used to expose the agent inside a container or to a remote
+/// host with a restricted set of commands e.g.
+///
+/// ```no_run
+///     extern crate assuan;
+///     extern crate gpgagent;
+///     let proxy = assuan::AssuanProxy::with_allow_list(&["GETINFO", "GET_PASSPHRASE"]);
+///     gpgagent::serve_proxy("/tmp/S.gpg-agent.container",
+///                           "/run/user/1000/gnupg/S.gpg-agent",
+///                           proxy).unwrap();
+/// ```
+///
+/// Each connection is handled in its own thread, and this function only
+/// returns if accepting a connection fails.
+pub fn serve_proxy<P, Q>(path: P, agent: Q, proxy: AssuanProxy) -> Result<(), GpgAgentError>
+    where P: AsRef<Path>, Q: AsRef<Path>
+{
+    serve(path.as_ref(), agent.as_ref(), proxy, false)
+}
+
+/// Listen for connections on the socket at `path` and forward them
+/// unchanged to the agent socket at `agent`, while writing a redacted
+/// transcript of every session to stderr.
+///
+/// Each transcript line is prefixed with a connection number, so
+/// concurrent sessions can be told apart. Point a client at the proxy
+/// socket to see what it asks the agent (e.g. which `OPTION display` it
+/// sets before a pinentry pops up).
+pub fn serve_debug_proxy<P, Q>(path: P, agent: Q) -> Result<(), GpgAgentError>
+    where P: AsRef<Path>, Q: AsRef<Path>
+{
+    serve(path.as_ref(), agent.as_ref(), AssuanProxy::new(), true)
+}
+
+fn serve(path: &Path, agent: &Path, proxy: AssuanProxy, debug: bool) -> Result<(), GpgAgentError> {
+    let listener = helpers::bind(path).map_err(AssuanError::from)?;
+    accept(listener, agent, proxy, debug, &AtomicBool::new(false))
+}
+
+fn accept(listener: UnixListener, agent: &Path, proxy: AssuanProxy, debug: bool, stop: &AtomicBool)
+    -> Result<(), GpgAgentError>
+{
+    for (id, client) in listener.incoming().enumerate() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let client = client.map_err(AssuanError::from)?;
+        let agent = agent.to_owned();
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            let res = forward(&proxy, client, agent, debug, id);
+            if let (true, Err(err)) = (debug, res) {
+                eprintln!("[{}] {}", id, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// The local end of agent forwarding, a socket named `S.gpg-agent` in a
+/// directory e.g. one bind mounted into a container or chroot. The
+/// socket is removed when this is dropped.
+///
+/// ```no_run
+///     extern crate assuan;
+///     extern crate gpgagent;
+///     let proxy = assuan::AssuanProxy::with_allow_list(&["GETINFO", "PKSIGN", "SIGKEY", "SETHASH"]);
+///     let socket = gpgagent::ForwardSocket::to_extra_socket("/srv/chroot/run/gnupg", proxy).unwrap();
+///     println!("Forwarding through {}", socket.path().display());
+/// ```
+///
+/// Connections are accepted in a background thread. Connections that are
+/// open when this is dropped are forwarded until the client closes them.
+pub struct ForwardSocket {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ForwardSocket {
+    /// Create the socket in `dir`, creating the directory if needed, and
+    /// forward its connections to the agent socket at `agent` using `proxy`
+    /// to filter commands. A stale socket left in `dir` is replaced.
+    pub fn bind<P, Q>(dir: P, agent: Q, proxy: AssuanProxy) -> Result<Self, GpgAgentError>
+        where P: AsRef<Path>, Q: AsRef<Path>
+    {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(AssuanError::from)?;
+        let path = dir.join("S.gpg-agent");
+        if path.exists() && UnixStream::connect(&path).is_err() {
+            fs::remove_file(&path).map_err(AssuanError::from)?;
+        }
+        let listener = UnixListener::bind(&path).map_err(AssuanError::from)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let agent = agent.as_ref().to_owned();
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let _ = accept(listener, &agent, proxy, false, &stop);
+            })
+        };
+        Ok(ForwardSocket { path, stop, thread: Some(thread) })
+    }
+
+    /// Same as `bind()`, forwarding to the extra socket of the local agent,
+    /// which already restricts what remote clients can do.
+    pub fn to_extra_socket<P: AsRef<Path>>(dir: P, proxy: AssuanProxy) -> Result<Self, GpgAgentError> {
+        let output = Command::new("gpgconf")
+            .args(["--list-dirs", "agent-extra-socket"])
+            .output()
+            .map_err(AssuanError::from)?;
+        if !output.status.success() {
+            return Err(GpgAgentError::SocketNotFound);
+        }
+        let agent = PathBuf::from(OsStr::from_bytes(output.stdout.trim_ascii_end()));
+        Self::bind(dir, agent, proxy)
+    }
+
+    /// The path of the forwarding socket
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ForwardSocket {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the accept loop so it sees the flag
+        let _ = helpers::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn forward(proxy: &AssuanProxy, client: UnixStream, agent: PathBuf, debug: bool, id: usize)
+    -> Result<(), AssuanError>
+{
+    let server = helpers::connect(&helpers::follow_redirect(&agent))?;
+    if debug {
+        let transcript = Prefixed { prefix: format!("[{}] ", id), line: Vec::new() };
+        proxy.run_with_transcript(client.try_clone()?, client, server.try_clone()?, server, transcript)
+    } else {
+        proxy.run(client.try_clone()?, client, server.try_clone()?, server)
+    }
+}
+
+/// Writes whole lines to stderr with a prefix, so lines from
+/// different connections do not get mixed
+struct Prefixed {
+    prefix: String,
+    line: Vec<u8>,
+}
+
+impl Write for Prefixed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for b in buf {
+            if self.line.is_empty() {
+                self.line.extend_from_slice(self.prefix.as_bytes());
+            }
+            self.line.push(*b);
+            if *b == b'\n' {
+                io::stderr().write_all(&self.line)?;
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpgagent_testutil::TestAgent;
+    use GpgAgent;
+
+    #[test]
+    fn forward_socket() {
+        let test_agent = TestAgent::new().unwrap();
+        let dir = test_agent.home().join("forward");
+        let proxy = AssuanProxy::with_allow_list(&["GETINFO"]);
+        let socket = ForwardSocket::bind(&dir, test_agent.socket_path(), proxy).unwrap();
+        assert_eq!(socket.path(), dir.join("S.gpg-agent"));
+
+        let mut agent = GpgAgent::from_path(socket.path()).unwrap();
+        assert!(agent.version().unwrap().starts_with('2'));
+        assert!(agent.clear_passphrase("forward:test").is_err());
+
+        drop(socket);
+        assert!(!dir.join("S.gpg-agent").exists());
+        // The open connection keeps working
+        assert!(agent.version().is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_socket() {
+        let test_agent = TestAgent::new().unwrap();
+        let name = format!("\0gpgagent-test-{}", std::process::id());
+        let agent_path = test_agent.socket_path().to_owned();
+        {
+            let name = name.clone();
+            thread::spawn(move || serve_proxy(name, agent_path, AssuanProxy::new()));
+        }
+
+        let mut agent = (0..50).find_map(|_| {
+            thread::sleep(std::time::Duration::from_millis(20));
+            GpgAgent::from_path(&name).ok()
+        }).unwrap();
+        assert!(agent.version().unwrap().starts_with('2'));
+        assert!(!Path::new(&name).exists());
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+
+use assuan::AssuanError;
+use rustc_serialize::hex::FromHex;
+
+use constants::error;
+use {GpgAgent, GpgAgentError};
+
+/// How the agent stores the secrets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Storage {
+    /// GET_SECRET and PUT_SECRET
+    Secret,
+    /// The passphrase cache, through PRESET_PASSPHRASE
+    Preset,
+}
+
+/// Named secrets kept in the memory of the agent, e.g. for a password
+/// manager
+///
+/// ```no_run
+///     # use gpgagent::{GpgAgent, SecretVault};
+///     let mut agent = GpgAgent::from_standard_paths().unwrap();
+///     let mut vault = SecretVault::new("myapp:");
+///     vault.put(&mut agent, "db", b"hunter2").unwrap();
+///     assert_eq!(vault.get(&mut agent, "db").unwrap(), Some(b"hunter2".to_vec()));
+/// ```
+///
+/// Agents with GET_SECRET and PUT_SECRET store them there. Older agents
+/// keep them in the passphrase cache, which needs the agent to be started
+/// with `allow-preset-passphrase` and always uses the default ttl, so
+/// `put()` fails there if a ttl was set.
+///
+/// The agent can not list its secrets, so `list()` only returns the
+/// names stored through this vault.
+#[derive(Clone, Debug)]
+pub struct SecretVault {
+    prefix: String,
+    /// Set by `ttl()`, 600 by default
+    ttl: Option<u32>,
+    names: BTreeSet<String>,
+    storage: Option<Storage>,
+}
+
+impl SecretVault {
+    /// A vault whose secrets are stored with `prefix` before their name,
+    /// to keep them apart from other cache entries.
+    pub fn new<P: Into<String>>(prefix: P) -> Self {
+        SecretVault {
+            prefix: prefix.into(),
+            ttl: None,
+            names: BTreeSet::new(),
+            storage: None,
+        }
+    }
+
+    /// How long secrets stay in the agent, in seconds. The default is 600,
+    /// the agent default for cached passphrases. Agents without PUT_SECRET
+    /// can not honour it, see above.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    fn cache_id(&self, name: &str) -> Result<String, GpgAgentError> {
+        if name.is_empty() || name.chars().any(|c| c == ' ' || c.is_control()) {
+            return Err(AssuanError::Other(format!("Invalid secret name: {}", name.escape_debug())).into());
+        }
+        Ok(format!("{}{}", self.prefix, name))
+    }
+
+    /// Ask the agent for PUT_SECRET once. Only an ERR reply selects the
+    /// passphrase cache, other failures are returned and asked again.
+    fn storage<R, W>(&mut self, agent: &mut GpgAgent<R, W>) -> Result<Storage, GpgAgentError>
+        where R: Read, W: Write
+    {
+        if let Some(storage) = self.storage {
+            return Ok(storage);
+        }
+        let storage = match agent.client.exec("HELP", &[b"PUT_SECRET"]) {
+            Ok(_) => Storage::Secret,
+            Err(AssuanError::Server { .. }) => Storage::Preset,
+            Err(err) => return Err(err.into()),
+        };
+        self.storage = Some(storage);
+        Ok(storage)
+    }
+
+    /// Store `secret` under `name`, replacing any previous one.
+    pub fn put<R, W>(&mut self, agent: &mut GpgAgent<R, W>, name: &str, secret: &[u8]) -> Result<(), GpgAgentError>
+        where R: Read, W: Write
+    {
+        let id = self.cache_id(name)?;
+        match self.storage(agent)? {
+            Storage::Secret => {
+                let ttl = self.ttl.unwrap_or(600).to_string();
+                // Left out of the command line, the agent inquires it
+                agent.client.exec_with_data("PUT_SECRET", &[id.as_bytes(), ttl.as_bytes()], secret)?;
+            }
+            // PRESET_PASSPHRASE has no ttl, rather than silently dropping it
+            Storage::Preset if self.ttl.is_some() =>
+                return Err(AssuanError::Other("The agent has no PUT_SECRET, secrets can not have a ttl".to_owned()).into()),
+            Storage::Preset => agent.preset_passphrase(&id, None, secret)?,
+        }
+        self.names.insert(name.to_owned());
+        Ok(())
+    }
+
+    /// The secret stored under `name`, `None` if there is none or it
+    /// expired.
+    pub fn get<R, W>(&mut self, agent: &mut GpgAgent<R, W>, name: &str) -> Result<Option<Vec<u8>>, GpgAgentError>
+        where R: Read, W: Write
+    {
+        let id = self.cache_id(name)?;
+        let res = match self.storage(agent)? {
+            Storage::Secret => agent.client.exec_data("GET_SECRET", &[id.as_bytes()]),
+            Storage::Preset => agent.client.exec("GET_PASSPHRASE", &[b"--no-ask", id.as_bytes(), b"X", b"X", b"X"])
+                .and_then(|res| res.message.from_hex().or(Err(AssuanError::UnexpectedResponse(res.message.into_bytes())))),
+        };
+        match res {
+            Ok(secret) => Ok(Some(secret)),
+            Err(err) => match err.error_code() {
+                Some(error::NO_DATA) | Some(error::NOT_FOUND) => {
+                    self.names.remove(name);
+                    Ok(None)
+                }
+                _ => Err(err.into()),
+            },
+        }
+    }
+
+    /// Remove the secret stored under `name`.
+    pub fn delete<R, W>(&mut self, agent: &mut GpgAgent<R, W>, name: &str) -> Result<(), GpgAgentError>
+        where R: Read, W: Write
+    {
+        let id = self.cache_id(name)?;
+        match self.storage(agent)? {
+            Storage::Secret => agent.client.exec("PUT_SECRET", &[b"--clear", id.as_bytes()]).map(|_| ())?,
+            Storage::Preset => agent.forget_passphrase(&id)?,
+        }
+        self.names.remove(name);
+        Ok(())
+    }
+
+    /// The names stored through this vault, in order. Some may have
+    /// expired since.
+    pub fn list(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|name| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs, process, thread};
+    use assuan::{proto, AssuanServer, CommandHandler, Responder};
+    use gpgagent_testutil::TestAgent;
+    use {UnixListener, UnixStream};
+
+    #[test]
+    fn put_get_delete() {
+        let test_agent = TestAgent::new().unwrap();
+        let mut agent = GpgAgent::from_path(test_agent.socket_path()).unwrap();
+        let mut vault = SecretVault::new("vault-test:");
+
+        assert_eq!(vault.get(&mut agent, "db").unwrap(), None);
+        vault.put(&mut agent, "db", b"hunter2").unwrap();
+        vault.put(&mut agent, "api", b"100% secret").unwrap();
+        assert_eq!(vault.get(&mut agent, "db").unwrap(), Some(b"hunter2".to_vec()));
+        assert_eq!(vault.get(&mut agent, "api").unwrap(), Some(b"100% secret".to_vec()));
+        assert_eq!(vault.list().collect::<Vec<_>>(), ["api", "db"]);
+
+        vault.delete(&mut agent, "db").unwrap();
+        assert_eq!(vault.get(&mut agent, "db").unwrap(), None);
+        assert_eq!(vault.list().collect::<Vec<_>>(), ["api"]);
+        assert!(vault.put(&mut agent, "two words", b"").is_err());
+    }
+
+    /// An agent with or without GET_SECRET and PUT_SECRET, keeping the
+    /// commands it receives
+    struct FakeAgent {
+        has_secrets: bool,
+        secrets: BTreeMap<String, Vec<u8>>,
+        commands: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl CommandHandler for FakeAgent {
+        fn command(&mut self, responder: &mut Responder, command: &str, args: &[u8]) -> Result<(), AssuanError> {
+            let args: Vec<String> = proto::split_args(args).iter()
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect();
+            self.commands.lock().unwrap().push(format!("{} {}", command, args.join(" ")));
+            let unknown = || AssuanError::server(error::NOT_FOUND, "Unknown command");
+            match (command, args.as_slice()) {
+                ("HELP", [name]) if name == "PUT_SECRET" && self.has_secrets => Ok(()),
+                ("HELP", _) => Err(unknown()),
+                ("PUT_SECRET", [clear, name]) if clear == "--clear" && self.has_secrets => {
+                    self.secrets.remove(name);
+                    Ok(())
+                }
+                ("PUT_SECRET", [name, _ttl]) if self.has_secrets => {
+                    let secret = responder.inquire("SECRET", "")?;
+                    self.secrets.insert(name.clone(), secret);
+                    Ok(())
+                }
+                ("GET_SECRET", [name]) if self.has_secrets => match self.secrets.get(name) {
+                    Some(secret) => responder.data(secret),
+                    None => Err(AssuanError::server(error::NOT_FOUND, "Not found")),
+                },
+                ("PRESET_PASSPHRASE", [name, _ttl, hex]) => {
+                    self.secrets.insert(name.clone(), hex.from_hex().unwrap());
+                    Ok(())
+                }
+                _ => Err(unknown()),
+            }
+        }
+    }
+
+    /// Run `f` with an agent connected to a `FakeAgent`, and return the
+    /// commands the fake received
+    fn with_fake_agent<F>(name: &str, has_secrets: bool, f: F) -> Vec<String>
+        where F: FnOnce(&mut GpgAgent<UnixStream, UnixStream>)
+    {
+        let path = env::temp_dir().join(format!("gpgagent-vault-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let mut fake = FakeAgent { has_secrets, secrets: BTreeMap::new(), commands: commands.clone() };
+        let server = thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            AssuanServer::new(stream.try_clone().unwrap(), stream).serve(&mut fake)
+        });
+
+        f(&mut GpgAgent::from_path(&path).unwrap());
+        server.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        let commands = commands.lock().unwrap().clone();
+        commands
+    }
+
+    #[test]
+    fn put_secret() {
+        let secret = vec![b'%'; 2000];
+        let commands = with_fake_agent("secret", true, |agent| {
+            let mut vault = SecretVault::new("vault-test:").ttl(60);
+            vault.put(agent, "db", &secret).unwrap();
+            assert_eq!(vault.get(agent, "db").unwrap(), Some(secret.clone()));
+            vault.delete(agent, "db").unwrap();
+            assert_eq!(vault.get(agent, "db").unwrap(), None);
+        });
+        // The secret is never part of a command
+        assert_eq!(commands, ["HELP PUT_SECRET",
+                              "PUT_SECRET vault-test:db 60",
+                              "GET_SECRET vault-test:db",
+                              "PUT_SECRET --clear vault-test:db",
+                              "GET_SECRET vault-test:db"]);
+    }
+
+    #[test]
+    fn preset_fallback() {
+        let commands = with_fake_agent("preset", false, |agent| {
+            let mut vault = SecretVault::new("vault-test:");
+            vault.put(agent, "db", b"hunter2").unwrap();
+            // The passphrase cache can not keep this ttl
+            let mut vault = SecretVault::new("vault-test:").ttl(60);
+            assert!(vault.put(agent, "db", b"hunter2").is_err());
+        });
+        assert_eq!(commands[0], "HELP PUT_SECRET");
+        assert!(commands[1].starts_with("PRESET_PASSPHRASE vault-test:db -1 "));
+        assert_eq!(commands[2], "HELP PUT_SECRET");
+        assert_eq!(commands.len(), 3);
+    }
+
+    #[test]
+    fn storage_probe_failure() {
+        // The agent goes away instead of answering HELP
+        let mut command = process::Command::new("sh");
+        command.args(["-c", "echo OK; read l"]);
+        let mut agent = GpgAgent::from_command(command).unwrap();
+        let mut vault = SecretVault::new("vault-test:");
+        assert!(vault.put(&mut agent, "db", b"hunter2").is_err());
+        assert_eq!(vault.storage, None);
+    }
+}